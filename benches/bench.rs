@@ -1,27 +1,35 @@
 #[macro_use]
 extern crate lazy_static;
 extern crate criterion;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use std::collections::HashSet;
-use std::iter::FromIterator;
 
 
+#[path = "../src/message/mod.rs"]
+mod message;
+
+// key_converterモジュールの中のrulesモジュールを使う
+#[path = "../src/key_converter/mod.rs"]
+mod key_converter;
+use key_converter::KeyConverter;
+use key_converter::rules::Rules;
+use key_converter::rules::key_rule::KeyRule;
+use key_converter::rules::key_rule::Key;
+use key_converter::rules::key_rule::keycode::Keycode;
+
 // rulesモジュールのベンチマークテストをする
 // Rules::filter関数はキーが押されるたびに呼ばれるので速度は速いほうがいい
-#[path = "../src/key_converter/rules/mod.rs"]
-mod rules;
-use rules::Rules;
-use rules::KeyRule;
-use rules::Key;
-use rules::keycode::Keycode;
-
 pub fn rules_benchmark(c: &mut Criterion) {
     let code = Keycode::new();
     let key_a = code.from_keyword("A").unwrap();
     let key_b = code.from_keyword("B").unwrap();
     let key_c = code.from_keyword("C").unwrap();
     let key_d = code.from_keyword("D").unwrap();
-    let key_e = code.from_keyword("E").unwrap();
     let key_enter = code.from_keyword("ENTER").unwrap();
     let key_ctrl = code.from_keyword("LEFTCTRL").unwrap();
     let key_alt = code.from_keyword("LEFTALT").unwrap();
@@ -36,41 +44,90 @@ pub fn rules_benchmark(c: &mut Criterion) {
         // 'ENTER + 'D -> 'ALT
         KeyRule::new(vec![Key::Con(key_enter), Key::Con(key_d)], vec![Key::Con(key_alt)]),
     ];
-    let rules = Rules::from_vec(&"", v);
+    let rules = Rules::from_vec("", None, v);
     let mut k = HashSet::new();
     k.insert(Key::Raw(key_a));
 
     // A -> 'Cの変換が行われる
-    c.bench_function("Rule::filter 1", |b| b.iter(|| rules.filter(&k)));
+    c.bench_function("Rule::filter 1", |b| b.iter(|| rules.filter(black_box(&k))));
 
     // A -> 'C, CTRL + 'C -> 'ENTER
     k.insert(Key::Raw(key_ctrl));
-    c.bench_function("Rule::filter 2", |b| b.iter(|| rules.filter(&k)));
+    c.bench_function("Rule::filter 2", |b| b.iter(|| rules.filter(black_box(&k))));
 
     // 最終的には'altに変換される
     k.insert(Key::Raw(key_b));
-    c.bench_function("Rule::filter 3", |b| b.iter(|| rules.filter(&k)));
+    c.bench_function("Rule::filter 3", |b| b.iter(|| rules.filter(black_box(&k))));
+}
+
+/// 修飾キーとの組み合わせのルールをたくさん作る。
+/// 最後のルールの右側は、次のルールの左側になる
+fn large_rules(n: usize) -> Rules {
+    let code = Keycode::new();
+    let modifiers: Vec<u16> = ["LEFTCTRL", "LEFTSHIFT", "LEFTALT", "LEFTMETA", "RIGHTCTRL"]
+        .iter().map(|m| code.from_keyword(m).unwrap()).collect();
+    let keys: Vec<u16> = (code.from_keyword("Q").unwrap()..code.from_keyword("P").unwrap() + 1)
+        .chain(code.from_keyword("A").unwrap()..code.from_keyword("L").unwrap() + 1)
+        .chain(code.from_keyword("Z").unwrap()..code.from_keyword("M").unwrap() + 1)
+        .chain(code.from_keyword("F1").unwrap()..code.from_keyword("F10").unwrap() + 1)
+        .collect();
+
+    let mut v = Vec::new();
+    'outer: for m1 in 0..modifiers.len() {
+        for m2 in m1..modifiers.len() {
+            for (i, k) in keys.iter().enumerate() {
+                if v.len() == n {
+                    break 'outer
+                }
+                let mut left = vec![Key::Raw(modifiers[m1])];
+                if m1 != m2 {
+                    left.push(Key::Raw(modifiers[m2]));
+                }
+                left.push(Key::Raw(*k));
+                v.push(KeyRule::new(left, vec![Key::Con(keys[(i + 1) % keys.len()])]));
+            }
+        }
+    }
+
+    Rules::from_vec("", None, v)
+}
+
+pub fn large_rules_benchmark(c: &mut Criterion) {
+    let code = Keycode::new();
+    let key = |s: &str| Key::Raw(code.from_keyword(s).unwrap());
+    let rules = large_rules(500);
+    assert_eq!(rules.get_list().len(), 500);
+
+    // どのルールにもマッチしない
+    let k: HashSet<Key> = vec![key("ENTER")].into_iter().collect();
+    c.bench_function("Rule::filter 500 rules, no match", |b| b.iter(|| rules.filter(black_box(&k))));
+
+    // 最後の方のルールにマッチする
+    let k: HashSet<Key> = vec![key("LEFTMETA"), key("RIGHTCTRL"), key("F10")].into_iter().collect();
+    c.bench_function("Rule::filter 500 rules, last", |b| b.iter(|| rules.filter(black_box(&k))));
+
+    // 修飾キーを押しているので、多くのルールが候補になる
+    let k: HashSet<Key> = vec![key("LEFTCTRL"), key("LEFTSHIFT"), key("LEFTALT"), key("A")]
+        .into_iter().collect();
+    c.bench_function("Rule::filter 500 rules, modifiers", |b| b.iter(|| rules.filter(black_box(&k))));
 }
 
 // key_converterモジュールのベンチマークテストをする
-#[path = "../src/key_converter/mod.rs"]
-mod key_converter;
-use key_converter::KeyConverter;
 pub fn key_converter_benchmark(c: &mut Criterion) {
-    let mut kc = KeyConverter::new("".as_bytes()).unwrap();
+    let mut kc = KeyConverter::new(&include_bytes!("../test/keymap.txt")[..]).unwrap();
     let code = Keycode::new();
     let key_a = code.from_keyword("A").unwrap();
     let key_n = code.from_keyword("N").unwrap();
     let key_h = code.from_keyword("H").unwrap();
     let key_ctrl = code.from_keyword("LEFTCTRL").unwrap();
-    
-    // A -> 'Bの変換が行われる
+
+    // 1つのキーの変換
     c.bench_function("KeyConverter 1", |b| b.iter(|| {
         kc.push(key_a);
         kc.leave(key_a);
     }));
 
-    // N -> 'L, H -> 'I, LEFTCTRL + 'L -> 'LEFT, LEFTCTRL + 'I -> 'RIGHT
+    // 修飾キーとの組み合わせ
     c.bench_function("KeyConverter 2", |b| b.iter(|| {
         kc.push(key_n);
         kc.push(key_h);
@@ -81,5 +138,5 @@ pub fn key_converter_benchmark(c: &mut Criterion) {
     }));
 }
 
-criterion_group!(benches, rules_benchmark, key_converter_benchmark);
-criterion_main!(benches);
\ No newline at end of file
+criterion_group!(benches, rules_benchmark, large_rules_benchmark, key_converter_benchmark);
+criterion_main!(benches);
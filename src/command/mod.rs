@@ -0,0 +1,471 @@
+//! サブコマンドを実行する
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+
+use control;
+use evlog;
+use explain;
+use interop;
+use interop::Target;
+use interop::Tool;
+use keys;
+use keyboard::{Event, Keyboard};
+use key_converter::KeyConverter;
+use key_converter::RepeatTiming;
+use key_converter::{DEFAULT_REPEAT_DELAY, DEFAULT_REPEAT_RATE};
+use key_converter::rules::Rules;
+use key_converter::rules::config;
+use key_converter::rules::config::Format;
+use key_converter::rules::key_rule::Key;
+use key_converter::rules::syntax::SyntaxTree;
+use message::Error;
+use message::Msg;
+use shutdown;
+use simulate;
+
+
+/// サブコマンドの名前のリスト
+const COMMANDS: &[&str] = &["convert", "fmt", "import", "export", "ctl", "record", "simulate", "check", "keys", "explain"];
+
+pub fn is_command(name: &str) -> bool {
+    COMMANDS.contains(&name)
+}
+
+/// サブコマンドを実行する。argsはサブコマンド名より後ろの引数
+pub fn run(name: &str, args: &[String]) -> Result<(), Error> {
+    match name {
+        "convert" => convert(args),
+        "fmt" => fmt(args),
+        "import" => import(args),
+        "export" => export(args),
+        "ctl" => ctl(args),
+        "record" => record(args),
+        "simulate" => simulate(args),
+        "check" => check(args),
+        "keys" => keys(args),
+        "explain" => explain(args),
+        _ => Err(Msg::InvalidOption(name.to_string()).into())
+    }
+}
+
+fn open(path: &str) -> Result<File, Error> {
+    File::open(path).map_err(|_| Error::new(Msg::CannotOpenFile(path.to_string())))
+}
+
+fn read_to_string<R: Read>(mut r: R, path: &str) -> Result<String, Error> {
+    let mut s = String::new();
+    r.read_to_string(&mut s)
+        .map_err(|_| Error::new(Msg::CannotOpenFile(path.to_string())))?;
+    Ok(s)
+}
+
+fn write(path: &str, s: &str) -> Result<(), Error> {
+    File::create(path)
+        .and_then(|mut f| f.write_all(s.as_bytes()))
+        .map_err(|_| Error::new(Msg::CannotWriteFile(path.to_string())))
+}
+
+/// オプションとそれ以外の引数に分ける
+fn split_options(args: &[String]) -> (Vec<&str>, Vec<&str>) {
+    args.iter().map(|a| a.as_str()).partition(|a| a.starts_with('-') && a.len() > 1)
+}
+
+/// keymap convert <input> <output>
+/// 出力の形式は拡張子で決める
+fn convert(args: &[String]) -> Result<(), Error> {
+    let input = args.first()
+        .ok_or_else(|| Error::new(Msg::MissingArgument("<input>".to_string())))?;
+    let output = args.get(1)
+        .ok_or_else(|| Error::new(Msg::MissingArgument("<output>".to_string())))?;
+
+    let sets = config::read_sets(open(input)?, Format::from_path(input))?;
+    let s = config::write_sets(sets, Format::from_path(output))?;
+
+    write(output, &s)
+}
+
+/// keymap fmt [--check] [--write] [<rule>...]
+/// ファイルがなければ標準入力を整形して標準出力に出力する
+fn fmt(args: &[String]) -> Result<(), Error> {
+    let (options, files) = split_options(args);
+    let mut check = false;
+    let mut overwrite = false;
+    for o in options {
+        match o {
+            "--check" => check = true,
+            "-w" | "--write" => overwrite = true,
+            _ => return Err(Msg::InvalidOption(o.to_string()).into())
+        }
+    }
+
+    if files.is_empty() {
+        let s = read_to_string(io::stdin(), "-")?;
+        let formatted = SyntaxTree::parse(&s)?.format();
+        if check && formatted != s {
+            return Err(Msg::NotFormatted("-".to_string()).into())
+        }
+        if !check {
+            print!("{}", formatted);
+        }
+        return Ok(())
+    }
+
+    let mut not_formatted = Vec::new();
+    for f in files {
+        let s = read_to_string(open(f)?, f)?;
+        let formatted = SyntaxTree::parse(&s).map_err(|e| e.with_file(f))?.format();
+
+        if check {
+            if formatted != s {
+                not_formatted.push(f);
+            }
+        } else if overwrite {
+            if formatted != s {
+                write(f, &formatted)?;
+            }
+        } else {
+            print!("{}", formatted);
+        }
+    }
+
+    if !not_formatted.is_empty() {
+        return Err(Msg::NotFormatted(not_formatted.join(", ")).into())
+    }
+
+    Ok(())
+}
+
+/// keymap import --from <tool> <file> [-o <output>]
+/// 変換できなかった部分は警告として標準エラー出力に出力する
+fn import(args: &[String]) -> Result<(), Error> {
+    let mut tool = None;
+    let mut input = None;
+    let mut output = None;
+    let mut iter = args.iter().map(|a| a.as_str());
+    while let Some(a) = iter.next() {
+        match a {
+            "--from" => tool = iter.next(),
+            "-o" | "--output" => output = iter.next(),
+            _ if a.starts_with("--from=") => tool = a.strip_prefix("--from="),
+            _ if a.starts_with('-') && a.len() > 1 => return Err(Msg::InvalidOption(a.to_string()).into()),
+            _ => input = Some(a),
+        }
+    }
+
+    let tool = tool
+        .ok_or_else(|| Error::new(Msg::MissingArgument("--from <tool>".to_string())))?;
+    let tool = Tool::from_str(tool)
+        .ok_or_else(|| Error::new(Msg::InvalidImportSource(tool.to_string())))?;
+    let input = input
+        .ok_or_else(|| Error::new(Msg::MissingArgument("<file>".to_string())))?;
+
+    let s = read_to_string(open(input)?, input)?;
+    let imported = interop::import(tool, &s).map_err(|e| e.with_file(input))?;
+    for w in &imported.warnings {
+        let line = if w.line > 0 { format!(":{}", w.line) } else { String::new() };
+        eprintln!("{}: [{}] {}{}: {}", Msg::WarningLabel, w.msg.code(), input, line, w.msg);
+    }
+
+    match output {
+        Some(o) => write(o, &config::write_sets(imported.sets, Format::from_path(o))?),
+        None => {
+            print!("{}", SyntaxTree::from_sets(&imported.sets).format());
+            Ok(())
+        }
+    }
+}
+
+/// keymap export --to <target> <rule> [--rule <name>] [-o <output>]
+/// 1つのキーを置き換えるルールのみを書き出す
+fn export(args: &[String]) -> Result<(), Error> {
+    let mut target = None;
+    let mut input = None;
+    let mut name = "";
+    let mut output = None;
+    let mut iter = args.iter().map(|a| a.as_str());
+    while let Some(a) = iter.next() {
+        match a {
+            "--to" => target = iter.next(),
+            "--rule" => name = iter.next().unwrap_or_default(),
+            "-o" | "--output" => output = iter.next(),
+            _ if a.starts_with("--to=") => target = a.strip_prefix("--to="),
+            _ if a.starts_with('-') && a.len() > 1 => return Err(Msg::InvalidOption(a.to_string()).into()),
+            _ => input = Some(a),
+        }
+    }
+
+    let target = target
+        .ok_or_else(|| Error::new(Msg::MissingArgument("--to <target>".to_string())))?;
+    let target = Target::from_str(target)
+        .ok_or_else(|| Error::new(Msg::InvalidExportTarget(target.to_string())))?;
+    let input = input
+        .ok_or_else(|| Error::new(Msg::MissingArgument("<rule>".to_string())))?;
+
+    let rules_list = config::load(open(input)?, Format::from_path(input))
+        .map_err(|e| e.with_file(input))?;
+    let rules = rules_list.get(name)
+        .ok_or_else(|| Error::new(Msg::UnknownRuleName(name.to_string())))?;
+    let s = interop::export(target, rules).map_err(|e| e.with_file(input))?;
+
+    match output {
+        Some(o) => write(o, &s),
+        None => {
+            print!("{}", s);
+            Ok(())
+        }
+    }
+}
+
+/// keymap ctl [--socket <path>] <command>
+/// 実行中のkeymapにコマンドを送る
+fn ctl(args: &[String]) -> Result<(), Error> {
+    let mut socket = control::DEFAULT_SOCKET;
+    let mut words = Vec::new();
+    let mut iter = args.iter().map(|a| a.as_str());
+    while let Some(a) = iter.next() {
+        match a {
+            "--socket" => socket = iter.next()
+                .ok_or_else(|| Error::new(Msg::MissingArgument("--socket <path>".to_string())))?,
+            _ if a.starts_with("--socket=") => socket = a.strip_prefix("--socket=").unwrap(),
+            _ if a.starts_with('-') && a.len() > 1 => return Err(Msg::InvalidOption(a.to_string()).into()),
+            _ => words.push(a),
+        }
+    }
+
+    if words.is_empty() {
+        return Err(Msg::MissingArgument("<command>".to_string()).into())
+    }
+    control::send(socket, &words.join(" "))
+}
+
+/// keymap record [--redact] <output>
+/// Ctrl-Cで終了するまで、キーボードから読んだイベントをevlogに書き込む。
+/// grabはしないので、他のプログラムがgrabしていると何も読めない
+fn record(args: &[String]) -> Result<(), Error> {
+    let mut redacted = false;
+    let mut output = None;
+    for a in args.iter().map(|a| a.as_str()) {
+        match a {
+            "--redact" => redacted = true,
+            _ if a.starts_with('-') && a.len() > 1 => return Err(Msg::InvalidOption(a.to_string()).into()),
+            _ => output = Some(a),
+        }
+    }
+    let output = output
+        .ok_or_else(|| Error::new(Msg::MissingArgument("<output>".to_string())))?;
+    let cannot_write = |_| Error::new(Msg::CannotWriteFile(output.to_string()));
+
+    let kbd = Keyboard::open()
+        .map_err(|e| Error::new(Msg::CannotOpenKeyboard(format!("{:?}", e.kind()))))?;
+    let file = File::create(output).map_err(cannot_write)?;
+    let mut writer = evlog::Writer::new(BufWriter::new(file), kbd.devices(), redacted)
+        .map_err(cannot_write)?;
+
+    shutdown::install(kbd.waker());
+    println!("{}", Msg::Recording(output.to_string()));
+    while !shutdown::requested() {
+        let e = match kbd.read_raw() {
+            Some(e) if redacted => evlog::redact(e),
+            e => e
+        };
+        if let Some(e) = e {
+            writer.write(&e).map_err(cannot_write)?;
+        }
+    }
+    writer.flush().map_err(cannot_write)
+}
+
+/// keymap simulate [--latch] [--repeat-delay <ms>] [--repeat-rate <n>] <rule> <evlog>
+/// 記録したイベントを変換して、仮想キーボードへの出力を表示する
+fn simulate(args: &[String]) -> Result<(), Error> {
+    let mut files = Vec::new();
+    let mut latch = false;
+    let mut repeat_delay = None;
+    let mut repeat_rate = None;
+    let mut iter = args.iter().map(|a| a.as_str());
+    while let Some(a) = iter.next() {
+        match a {
+            "--latch" => latch = true,
+            "--repeat-delay" | "--repeat-rate" => {
+                let v = iter.next()
+                    .ok_or_else(|| Error::new(Msg::MissingArgument(format!("{} <n>", a))))?;
+                let n = v.parse::<u64>().ok()
+                    .filter(|n| a == "--repeat-delay" || RepeatTiming::is_valid_rate(*n))
+                    .ok_or_else(|| Error::new(Msg::InvalidOption(format!("{} {}", a, v))))?;
+                if a == "--repeat-delay" {
+                    repeat_delay = Some(n);
+                } else {
+                    repeat_rate = Some(n);
+                }
+            },
+            _ if a.starts_with('-') && a.len() > 1 => return Err(Msg::InvalidOption(a.to_string()).into()),
+            _ => files.push(a),
+        }
+    }
+
+    let rule = files.first()
+        .ok_or_else(|| Error::new(Msg::MissingArgument("<rule>".to_string())))?;
+    let trace = files.get(1)
+        .ok_or_else(|| Error::new(Msg::MissingArgument("<evlog>".to_string())))?;
+
+    let rules_list = config::load(open(rule)?, Format::from_path(rule))
+        .map_err(|e| e.with_file(rule))?;
+    let events = evlog::parse(&read_to_string(open(trace)?, trace)?)
+        .map_err(|e| e.with_file(trace))?;
+
+    let mut kc = KeyConverter::from_rules_list(rules_list);
+    kc.set_latch(latch);
+    if repeat_delay.is_some() || repeat_rate.is_some() {
+        kc.set_repeat_timing(Some(RepeatTiming::new(
+            repeat_delay.unwrap_or(DEFAULT_REPEAT_DELAY),
+            repeat_rate.unwrap_or(DEFAULT_REPEAT_RATE))));
+    }
+
+    print!("{}", simulate::run(&mut kc, &events));
+    Ok(())
+}
+
+/// keymap check <rule>...
+/// キーボードは使わずにルールファイルを読み、すべてのエラーを表示する。
+/// エラーがなければルール名ごとのルールの数と継承を表示する
+fn check(args: &[String]) -> Result<(), Error> {
+    let (options, files) = split_options(args);
+    if let Some(o) = options.first() {
+        return Err(Msg::InvalidOption(o.to_string()).into())
+    }
+    if files.is_empty() {
+        return Err(Msg::MissingArgument("<rule>".to_string()).into())
+    }
+
+    let mut count = 0;
+    for f in files {
+        let (rules_list, errors) = match open(f) {
+            Ok(r) => {
+                let (rules_list, errors) = config::check(r, Format::from_path(f));
+                (rules_list, errors.into_iter().map(|e| e.with_file(f)).collect())
+            },
+            Err(e) => (HashMap::new(), vec![e])
+        };
+
+        for e in &errors {
+            println!("{}: {}", Msg::ErrorLabel, e);
+        }
+        count += errors.len();
+        if errors.is_empty() {
+            print!("{}", summary(f, &rules_list));
+        }
+    }
+
+    if count > 0 {
+        return Err(Msg::CheckFailed(count).into())
+    }
+    Ok(())
+}
+
+/// ルール名ごとのルールの数と、継承しているルール名を並べる
+fn summary(path: &str, rules_list: &HashMap<String, Rules>) -> String {
+    let mut names: Vec<&String> = rules_list.keys().collect();
+    names.sort();
+    let width = names.iter().map(|n| n.chars().count()).max().unwrap_or(0) + 1;
+
+    let mut s = format!("{}\n", path);
+    for name in names {
+        let rules = &rules_list[name];
+        s += &format!("  {:<width$}  {:>4} rules", format!("@{}", name), rules.get_list().len(),
+                      width = width);
+
+        // 継承を順にたどる。継承の循環はエラーになっているので止まる
+        let mut chain = vec![format!("@{}", name)];
+        let mut extend = rules.get_extend();
+        while let Some(e) = extend {
+            chain.push(format!("@{}", e));
+            extend = rules_list.get(e).and_then(|r| r.get_extend());
+        }
+        if chain.len() > 1 {
+            s += &format!("  ({})", chain.join(" : "));
+        }
+        s += "\n";
+    }
+    s
+}
+
+/// keymap keys [--press] [<query>]
+/// キーの名前を出力する。--pressではCtrl-Cで終了するまで、押したキーの名前を出力する
+fn keys(args: &[String]) -> Result<(), Error> {
+    let mut press = false;
+    let mut query = None;
+    for a in args.iter().map(|a| a.as_str()) {
+        match a {
+            "--press" => press = true,
+            _ if a.starts_with('-') && a.len() > 1 => return Err(Msg::InvalidOption(a.to_string()).into()),
+            _ => query = Some(a),
+        }
+    }
+
+    if press {
+        return press_keys()
+    }
+    let entries = match query {
+        Some(q) => keys::search(q),
+        None => keys::list(),
+    };
+    print!("{}", keys::format(&entries));
+    Ok(())
+}
+
+/// 押したキーの名前を出力する。
+/// grabはしないので、押したキーはそのまま入力される
+fn press_keys() -> Result<(), Error> {
+    let mut kbd = Keyboard::open()
+        .map_err(|e| Error::new(Msg::CannotOpenKeyboard(format!("{:?}", e.kind()))))?;
+    kbd.set_escape(Vec::new());
+    let list = keys::list();
+
+    shutdown::install(kbd.waker());
+    println!("{}", Msg::PressKeys);
+    while !shutdown::requested() {
+        if let Event::Key(code, 1) = kbd.read_key_until(None) {
+            let entry = list.iter().find(|e| e.code == code).cloned()
+                .unwrap_or(keys::Entry { code, names: vec![Key::Raw(code).to_string()] });
+            print!("{}", keys::format(&[entry]));
+        }
+    }
+    Ok(())
+}
+
+/// keymap explain [--rule <name>] <rule> <keys>
+/// キーボードは使わずに、キーの組み合わせにルールを適用する手順を表示する
+fn explain(args: &[String]) -> Result<(), Error> {
+    let mut name = "";
+    let mut words = Vec::new();
+    let mut iter = args.iter().map(|a| a.as_str());
+    while let Some(a) = iter.next() {
+        match a {
+            "--rule" => name = iter.next()
+                .ok_or_else(|| Error::new(Msg::MissingArgument("--rule <name>".to_string())))?,
+            _ if a.starts_with("--rule=") => name = a.strip_prefix("--rule=").unwrap(),
+            _ if a.starts_with('-') && a.len() > 1 => return Err(Msg::InvalidOption(a.to_string()).into()),
+            _ => words.push(a),
+        }
+    }
+    let name = name.strip_prefix('@').unwrap_or(name);
+
+    let input = words.first()
+        .ok_or_else(|| Error::new(Msg::MissingArgument("<rule>".to_string())))?;
+    // "LeftCtrl + H"も"LeftCtrl" "+" "H"も同じ
+    if words.len() < 2 {
+        return Err(Msg::MissingArgument("<keys>".to_string()).into())
+    }
+    let keys = explain::parse_chord(&words[1..].join(" "))?;
+
+    let rules_list = config::load(open(input)?, Format::from_path(input))
+        .map_err(|e| e.with_file(input))?;
+    let rules = rules_list.get(name)
+        .ok_or_else(|| Error::new(Msg::UnknownRuleName(name.to_string())))?;
+
+    print!("{}", explain::run(rules, &keys));
+    Ok(())
+}
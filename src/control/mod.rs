@@ -0,0 +1,401 @@
+//! 実行中のkeymapを操作するためのUnixドメインソケット。
+//!
+//! 1行に1つのコマンドを送ると、結果を1行のJSONで返す。
+//!
+//! ```text
+//! status          {"rules":"SYMBOL","paused":false,"pressed":["HENKAN"],"output":[]}
+//! switch @NAME    {"ok":true}
+//! pause           {"ok":true}
+//! resume          {"ok":true}
+//! reload          {"ok":true}
+//! subscribe       状態が変わるたびにstatusと同じ行を送り続ける
+//! ```
+//!
+//! 失敗したときは{"ok":false,"code":"E114","error":"..."}を返す。
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use libc;
+use serde::Serialize;
+use serde_json;
+
+use key_converter::KeyConverter;
+use key_converter::Output;
+use key_converter::rules::key_rule::Key;
+use keyboard::Waker;
+use message::Error;
+use message::Msg;
+
+
+pub const DEFAULT_SOCKET: &str = "/run/keymap.sock";
+
+// 結果を書き込むのを諦めるまでの時間
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// ソケットで受け付けるコマンド
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Status,
+    Switch(String), // ルール名。""は@のルール
+    Pause,
+    Resume,
+    Reload,
+    Subscribe,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, Error> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["status"] => Ok(Command::Status),
+            ["switch", name] => Ok(Command::Switch(
+                name.strip_prefix('@').unwrap_or(name).to_string())),
+            ["pause"] => Ok(Command::Pause),
+            ["resume"] => Ok(Command::Resume),
+            ["reload"] => Ok(Command::Reload),
+            ["subscribe"] => Ok(Command::Subscribe),
+            _ => Err(Msg::UnknownControlCommand(line.trim().to_string()).into())
+        }
+    }
+}
+
+/// statusとsubscribeで返す状態
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Status {
+    pub rules: String,
+    pub paused: bool,
+    pub pressed: Vec<String>, // 押されている実際のキー
+    pub output: Vec<String>,  // 仮想キーボードで押されているキー
+}
+
+impl Status {
+    pub fn new(kc: &KeyConverter, pause: &Pause) -> Status {
+        let passed = pause.passed.iter().map(|c| Key::Raw(*c).to_string());
+        Status {
+            rules: kc.get_rules_name().to_string(),
+            paused: pause.paused,
+            pressed: kc.pressed().iter().map(|p| p.key.to_string())
+                .chain(passed.clone())
+                .collect(),
+            output: kc.outputs().iter()
+                .filter_map(|k| k.to_u16())
+                .map(|c| Key::Raw(c).to_string())
+                .chain(passed)
+                .collect(),
+        }
+    }
+}
+
+/// status以外のコマンドの結果
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reply {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Reply {
+    fn from_result(result: Result<(), Error>) -> Reply {
+        match result {
+            Ok(()) => Reply { ok: true, code: None, error: None },
+            Err(e) => Reply {
+                ok: false,
+                code: Some(e.code().to_string()),
+                error: Some(e.msg.to_string()),
+            }
+        }
+    }
+}
+
+/// 一時停止の状態
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Pause {
+    paused: bool,
+    passed: Vec<u16>, // 一時停止中に押したキー。再開しても離すまで変換しない
+}
+
+impl Pause {
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// キーボードから読んだキーを変換せずにそのまま入力するときは、その出力を返す。
+    /// KeyConverterに渡すときはNone
+    pub fn input(&mut self, code: u16, state: i32) -> Option<Output> {
+        if self.passed.contains(&code) {
+            return match state {
+                0 => {
+                    self.passed.retain(|c| *c != code);
+                    Some(Output { leave: vec![code], ..Output::default() })
+                },
+                2 => Some(Output { repeat: vec![code], ..Output::default() }),
+                _ => Some(Output::default())
+            }
+        }
+
+        if self.paused && state == 1 {
+            self.passed.push(code);
+            return Some(Output { push: vec![code], ..Output::default() })
+        }
+        None
+    }
+}
+
+/// ソケットで受け取ったコマンドと、結果を返す接続
+pub struct Request {
+    pub command: Command,
+    stream: UnixStream,
+}
+
+impl Request {
+    pub fn reply_status(mut self, status: &Status) {
+        write_line(&mut self.stream, status);
+    }
+
+    pub fn reply(mut self, result: Result<(), Error>) {
+        write_line(&mut self.stream, &Reply::from_result(result));
+    }
+}
+
+/// ソケットを所有者だけが読み書きできるようにし、sudoで実行したときは
+/// SUDO_UIDのユーザーを所有者にする
+fn restrict(path: &str) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    let uid = match env::var("SUDO_UID").ok().and_then(|u| u.parse::<libc::uid_t>().ok()) {
+        Some(uid) => uid,
+        None => return Ok(())
+    };
+    let gid = env::var("SUDO_GID").ok().and_then(|g| g.parse().ok()).unwrap_or(libc::gid_t::MAX);
+
+    let c_path = ::std::ffi::CString::new(path)?;
+    if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(())
+}
+
+pub struct Server {
+    path: String,
+    receiver: mpsc::Receiver<Request>,
+    subscribers: Vec<UnixStream>,
+    last: Option<Status>, // 最後にsubscribersに送った状態
+}
+
+impl Server {
+    /// ソケットを作成して接続を待ち始める。
+    /// コマンドを受け取るとwakerでメインのループを起こす
+    pub fn start(path: &str, waker: Waker) -> Result<Server, Error> {
+        let fail = |e: io::Error| Error::new(
+            Msg::CannotOpenSocket(path.to_string(), format!("{:?}", e.kind())));
+
+        // 前回のkeymapが残したソケットは消す。使われていれば消さない
+        if Path::new(path).exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(fail(io::ErrorKind::AddrInUse.into()))
+            }
+            fs::remove_file(path).map_err(fail)?;
+        }
+        let listener = UnixListener::bind(path).map_err(fail)?;
+
+        // statusとsubscribeで押したキーがすべて見えるので、所有者だけが使えるようにする。
+        // sudoで実行したときは、実行したユーザーを所有者にする
+        if let Err(e) = restrict(path) {
+            let _ = fs::remove_file(path);
+            return Err(fail(e))
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let sender = sender.clone();
+                let waker = waker.clone();
+                thread::spawn(move || read_commands(stream, sender, waker));
+            }
+        });
+
+        Ok(Server {
+            path: path.to_string(),
+            receiver,
+            subscribers: Vec::new(),
+            last: None,
+        })
+    }
+
+    pub fn try_recv(&self) -> Option<Request> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// 今の状態を送り、これから状態が変わるたびに送る
+    pub fn subscribe(&mut self, mut request: Request, status: &Status) {
+        // 読まないクライアントのためにキーの入力を止めない
+        if request.stream.set_nonblocking(true).is_ok()
+            && write_line(&mut request.stream, status) {
+            self.subscribers.push(request.stream);
+        }
+    }
+
+    /// 状態が前回から変わっていればsubscribeしている接続に送る。
+    /// 送れなかった接続は閉じる
+    pub fn publish(&mut self, status: Status) {
+        if self.last.as_ref() == Some(&status) {
+            return
+        }
+        self.subscribers.retain_mut(|s| write_line(s, &status));
+        self.last = Some(status);
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// 接続から1行ずつコマンドを読んでメインのループに送る
+fn read_commands(stream: UnixStream, sender: mpsc::Sender<Request>, waker: Waker) {
+    // 結果を読まないクライアントでメインのループを止めない
+    stream.set_write_timeout(Some(WRITE_TIMEOUT)).ok();
+    let mut reader = match stream.try_clone() {
+        Ok(s) => BufReader::new(s),
+        Err(_) => return
+    };
+
+    let mut line = String::new();
+    while reader.read_line(&mut line).is_ok_and(|n| n > 0) {
+        if !line.trim().is_empty() {
+            match Command::parse(&line) {
+                Ok(command) => {
+                    let subscribe = command == Command::Subscribe;
+                    let stream = match stream.try_clone() {
+                        Ok(s) => s,
+                        Err(_) => return
+                    };
+                    if sender.send(Request { command, stream }).is_err() {
+                        return
+                    }
+                    waker.wake();
+                    // subscribeした接続はメインのループが持つ
+                    if subscribe {
+                        return
+                    }
+                },
+                Err(e) => {
+                    let mut s = &stream;
+                    write_line(&mut s, &Reply::from_result(Err(e)));
+                }
+            }
+        }
+        line.clear();
+    }
+}
+
+/// JSONを1行書き込む。書き込めなければfalse
+fn write_line<W: Write, T: Serialize>(w: &mut W, value: &T) -> bool {
+    let mut s = serde_json::to_string(value).unwrap();
+    s.push('\n');
+    w.write_all(s.as_bytes()).is_ok()
+}
+
+/// keymap ctlの本体。コマンドを送り、返ってきた行をそのまま出力する。
+/// subscribeは接続が切れるまで出力し続ける
+pub fn send(path: &str, line: &str) -> Result<(), Error> {
+    let command = Command::parse(line)?;
+    let mut stream = UnixStream::connect(path)
+        .map_err(|_| Error::new(Msg::CannotConnect(path.to_string())))?;
+    stream.write_all(format!("{}\n", line.trim()).as_bytes())
+        .map_err(|_| Error::new(Msg::CannotConnect(path.to_string())))?;
+
+    for l in BufReader::new(stream).lines() {
+        let l = match l {
+            Ok(l) => l,
+            Err(_) => break
+        };
+        if let Ok(Reply { ok: false, error: Some(e), .. }) = serde_json::from_str(&l) {
+            return Err(Msg::ControlFailed(e).into())
+        }
+        println!("{}", l);
+        if command != Command::Subscribe {
+            break
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use key_converter::rules::key_rule::keycode::*;
+
+    #[test]
+    fn test_command() {
+        assert_eq!(Command::parse("status\n").unwrap(), Command::Status);
+        assert_eq!(Command::parse("switch @SYMBOL").unwrap(), Command::Switch("SYMBOL".to_string()));
+        assert_eq!(Command::parse("switch @").unwrap(), Command::Switch(String::new()));
+        assert_eq!(Command::parse("switch").unwrap_err().code(), "E015");
+        assert_eq!(Command::parse("quit").unwrap_err().code(), "E015");
+    }
+
+    #[test]
+    fn test_status() {
+        let mut kc = KeyConverter::new(r#"
+        CAPSLOCK -> 'LEFTCTRL
+        "#.as_bytes()).unwrap();
+        let mut pause = Pause::default();
+
+        kc.push(KEY_CAPSLOCK);
+        assert!(pause.input(KEY_A, 1).is_none());
+        kc.push(KEY_A);
+        let status = Status::new(&kc, &pause);
+        assert_eq!(status.pressed, vec!["CAPSLOCK", "A"]);
+        assert_eq!(status.output, vec!["A", "LEFTCTRL"]);
+        assert_eq!(serde_json::to_string(&status).unwrap(),
+            r#"{"rules":"","paused":false,"pressed":["CAPSLOCK","A"],"output":["A","LEFTCTRL"]}"#);
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut pause = Pause::default();
+        pause.set(true);
+        assert_eq!(pause.input(KEY_A, 1).unwrap().push, vec![KEY_A]);
+        // 再開しても、一時停止中に押したキーは離すまでそのまま入力する
+        pause.set(false);
+        assert_eq!(pause.input(KEY_A, 2).unwrap().repeat, vec![KEY_A]);
+        assert_eq!(pause.input(KEY_A, 0).unwrap().leave, vec![KEY_A]);
+        assert!(pause.input(KEY_A, 1).is_none());
+    }
+
+    #[test]
+    fn test_socket_mode() {
+        let path = ::std::env::temp_dir().join(format!("keymap-control-{}.sock", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        let (waker, _receiver) = Waker::channel();
+
+        // 押したキーが見えるので、所有者以外は接続できない
+        let server = Server::start(path, waker).unwrap();
+        assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        drop(server);
+        assert!(!Path::new(path).exists());
+    }
+
+    #[test]
+    fn test_reply() {
+        let r = Reply::from_result(Err(Msg::UnknownRuleName("X".to_string()).into()));
+        assert_eq!(r.code.as_deref(), Some("E114"));
+        assert_eq!(serde_json::to_string(&Reply::from_result(Ok(()))).unwrap(), r#"{"ok":true}"#);
+    }
+}
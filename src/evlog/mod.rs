@@ -0,0 +1,203 @@
+//! keymap recordが書き出すイベントの記録(evlog)。
+//!
+//! 1行に1つの要素を書くテキストの形式で、#から行末まではコメント。
+//!
+//! ```text
+//! keymap-evlog 1
+//! device 0 /dev/input/event3
+//! redacted
+//! 0.000000 0 1 29 1
+//! 0.000000 0 0 0 0
+//! 0.250000 0 1 240 1
+//! 0.250000 0 0 0 0
+//! ```
+//!
+//! - 1行目は形式の名前とバージョン
+//! - `device <番号> <ファイル名>`はイベントを読んだデバイス
+//! - `redacted`があれば、修飾キーとファンクションキー以外のキーはUNKNOWNになっている
+//! - それ以外の行はイベントで、`<秒> <デバイスの番号> <type> <code> <value>`。
+//!   秒は最初のイベントからの時間で、マイクロ秒まで書く
+//!
+//! 手で書くときは、イベントを`<秒> push|leave|repeat <キーの名前>`とも書ける。
+//! 1行目も省略できる。
+//!
+//! ```text
+//! 0.0 push CAPSLOCK
+//! 0.1 push A
+//! 0.2 leave A
+//! 0.3 leave CAPSLOCK
+//! ```
+
+use std::io;
+use std::io::Write;
+use std::time::Duration;
+
+use keyboard::RawEvent;
+use key_converter::rules::key_rule::Key;
+use key_converter::rules::key_rule::keycode::*;
+use message::Error;
+use message::Msg;
+
+
+pub const HEADER: &str = "keymap-evlog 1";
+
+// evdevのイベントの種類
+pub const EV_KEY: u16 = 1;
+const EV_MSC: u16 = 4;
+
+/// 修飾キーとファンクションキー以外のキーを隠す。
+/// キーコードはUNKNOWNにして、スキャンコードを含むEV_MSCのイベントは捨てる
+pub fn redact(e: RawEvent) -> Option<RawEvent> {
+    match e.ty {
+        EV_KEY if !is_modifier(e.code) && !is_function_key(e.code) =>
+            Some(RawEvent { code: KEY_UNKNOWN, ..e }),
+        EV_MSC => None,
+        _ => Some(e)
+    }
+}
+
+/// F1からF24まで
+fn is_function_key(code: u16) -> bool {
+    let name = Key::Raw(code).to_string();
+    name.len() > 1 && name.starts_with('F') && name[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+/// キーの状態の名前。0は離す、1は押す、2はリピート
+pub fn state_name(value: i32) -> &'static str {
+    match value {
+        0 => "leave",
+        1 => "push",
+        _ => "repeat",
+    }
+}
+
+/// evlogを読んでイベントのリストにする。deviceの行は読み飛ばす
+pub fn parse(s: &str) -> Result<Vec<RawEvent>, Error> {
+    let mut events = Vec::new();
+    for (i, l) in s.lines().enumerate() {
+        let l = l.split('#').next().unwrap_or_default().trim();
+        let words: Vec<&str> = l.split_whitespace().collect();
+        let invalid = || Error::new(Msg::InvalidEvent(l.to_string())).with_line(i + 1);
+
+        let event = match words.as_slice() {
+            [] | ["device", ..] | ["redacted"] => continue,
+            ["keymap-evlog", _] if l == HEADER => continue,
+            [t, state, key] => {
+                let value = match *state {
+                    "leave" => 0,
+                    "push" => 1,
+                    "repeat" => 2,
+                    _ => return Err(invalid())
+                };
+                let code = match Key::from_str(key) {
+                    Ok(Key::Raw(c)) => c,
+                    _ => return Err(invalid())
+                };
+                RawEvent { device: 0, time: parse_time(t).ok_or_else(invalid)?, ty: EV_KEY, code, value }
+            },
+            [t, device, ty, code, value] => RawEvent {
+                device: device.parse().map_err(|_| invalid())?,
+                time: parse_time(t).ok_or_else(invalid)?,
+                ty: ty.parse().map_err(|_| invalid())?,
+                code: code.parse().map_err(|_| invalid())?,
+                value: value.parse().map_err(|_| invalid())?,
+            },
+            _ => return Err(invalid())
+        };
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// 秒をマイクロ秒まで書く
+pub fn format_time(t: Duration) -> String {
+    format!("{}.{:06}", t.as_secs(), t.subsec_micros())
+}
+
+fn parse_time(s: &str) -> Option<Duration> {
+    s.parse::<f64>().ok()
+        .filter(|t| t.is_finite() && *t >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// イベントを1つずつevlogに書き込む
+pub struct Writer<W: Write> {
+    w: W,
+    start: Option<Duration>, // 最初のイベントの時刻
+}
+
+impl<W: Write> Writer<W> {
+    /// ヘッダーとデバイスのリストを書き込む
+    pub fn new(mut w: W, devices: &[String], redacted: bool) -> io::Result<Writer<W>> {
+        writeln!(w, "{}", HEADER)?;
+        for (i, d) in devices.iter().enumerate() {
+            writeln!(w, "device {} {}", i, d)?;
+        }
+        if redacted {
+            writeln!(w, "redacted")?;
+        }
+        Ok(Writer { w, start: None })
+    }
+
+    pub fn write(&mut self, e: &RawEvent) -> io::Result<()> {
+        let start = *self.start.get_or_insert(e.time);
+        writeln!(self.w, "{} {} {} {} {}",
+                 format_time(e.time.saturating_sub(start)), e.device, e.ty, e.code, e.value)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use keyboard::RawEvent;
+    use key_converter::rules::key_rule::keycode::*;
+    use super::*;
+
+    fn key(ms: u64, code: u16, value: i32) -> RawEvent {
+        RawEvent { device: 0, time: Duration::from_millis(ms), ty: 1, code, value }
+    }
+
+    #[test]
+    fn test_writer() {
+        let mut buf = Vec::new();
+        {
+            let mut w = Writer::new(&mut buf, &["/dev/input/event3".to_string()], true).unwrap();
+            for e in [key(1000, KEY_LEFTCTRL, 1), key(1250, KEY_A, 1), key(2500, KEY_F5, 1)] {
+                if let Some(e) = redact(e) {
+                    w.write(&e).unwrap();
+                }
+            }
+        }
+        assert_eq!(String::from_utf8(buf).unwrap(), "\
+keymap-evlog 1
+device 0 /dev/input/event3
+redacted
+0.000000 0 1 29 1
+0.250000 0 1 240 1
+1.500000 0 1 63 1
+");
+        assert!(redact(RawEvent { ty: 4, ..key(0, 4, 30) }).is_none());
+    }
+
+    #[test]
+    fn test_parse() {
+        let events = parse("\
+keymap-evlog 1
+device 0 /dev/input/event3
+0.000000 0 1 29 1   # LEFTCTRL
+0.250 push A
+0.5 leave A
+").unwrap();
+        assert_eq!(events, vec![key(0, KEY_LEFTCTRL, 1), key(250, KEY_A, 1), key(500, KEY_A, 0)]);
+
+        let e = parse("0.0 push A\n0.1 press B\n").unwrap_err();
+        assert_eq!((e.code(), e.line), ("E017", Some(2)));
+        assert!(parse("keymap-evlog 2\n").is_err());
+        assert!(parse("0.1 push 'A\n").is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! keymap explainで、キーの組み合わせにルールを適用する手順を説明する。
+//!
+//! 実際のキーボードを使わず、組み合わせのキーが押された順に押されているものとして
+//! Rules::filter_stepsで変換し、適用したルールを順に出力する。
+//!
+//! ```text
+//! @NAV  CAPSLOCK + LEFTCTRL + H
+//! 1. CAPSLOCK -> $NAV
+//!    consumed: CAPSLOCK
+//!    produced: $NAV
+//! 2. $NAV + H -> 'LEFT
+//!    consumed: $NAV + H
+//!    produced: 'LEFT
+//! output: LEFTCTRL + 'LEFT
+//! ```
+
+use key_converter::rules::Rules;
+use key_converter::rules::key_rule::Key;
+use message::Error;
+use message::Msg;
+
+
+/// "LeftCtrl + H"のようなキーの組み合わせを押された順のキーにする。
+/// 実際のキーだけを使える
+pub fn parse_chord(s: &str) -> Result<Vec<Key>, Error> {
+    let mut keys = Vec::new();
+    for k in s.split('+').map(|k| k.trim()) {
+        match Key::from_str(k) {
+            Ok(Key::Raw(c)) if !keys.contains(&Key::Raw(c)) => keys.push(Key::Raw(c)),
+            Ok(Key::Raw(_)) => (),
+            _ => return Err(Msg::InvalidKeycode(k.to_string()).into())
+        }
+    }
+    Ok(keys)
+}
+
+/// keysにrulesを適用して、適用したルールと変換後のキーを文字列にする
+pub fn run(rules: &Rules, keys: &[Key]) -> String {
+    let matched = rules.filter_steps(keys);
+    let mut s = format!("@{}  {}\n", rules.get_name(), join(keys));
+
+    for (i, r) in matched.rules.iter().enumerate() {
+        let rule = &rules.get_list()[*r];
+        s += &format!("{}. {}\n", i + 1, rule.to_source());
+        s += &format!("   consumed: {}\n", join(&rule.k));
+        s += &format!("   produced: {}\n", join(&rule.v));
+    }
+    if matched.rules.is_empty() {
+        s += "no rule matched\n";
+    }
+    s += &format!("output: {}\n", join(&matched.keys));
+    s
+}
+
+/// キーを" + "でつなぐ。空なら"(none)"
+fn join(keys: &[Key]) -> String {
+    if keys.is_empty() {
+        return "(none)".to_string()
+    }
+    keys.iter().map(|k| k.to_source()).collect::<Vec<String>>().join(" + ")
+}
+
+
+#[cfg(test)]
+mod test {
+    use key_converter::rules::RulesParser;
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let rules_list = RulesParser::parse(r#"
+        CAPSLOCK -> $NAV
+        @NAV : @ [passthrough=block]
+        $NAV + H -> 'LEFT
+        LEFTCTRL -> 'LEFTCTRL
+        "#.as_bytes()).unwrap();
+
+        // passthrough=blockなので、どのルールにもマッチしなかったJは入力しない
+        let keys = parse_chord("CapsLock + LeftCtrl + H + J").unwrap();
+        assert_eq!(run(&rules_list["NAV"], &keys), "\
+@NAV  CAPSLOCK + LEFTCTRL + H + J
+1. CAPSLOCK -> $NAV
+   consumed: CAPSLOCK
+   produced: $NAV
+2. $NAV + H -> 'LEFT
+   consumed: $NAV + H
+   produced: 'LEFT
+3. LEFTCTRL -> 'LEFTCTRL
+   consumed: LEFTCTRL
+   produced: 'LEFTCTRL
+output: 'LEFT + 'LEFTCTRL
+");
+        assert_eq!(run(&rules_list[""], &parse_chord("A").unwrap()), "\
+@  A
+no rule matched
+output: A
+");
+
+        // ルールファイルと同じ書き方で出力する
+        let rules_list = RulesParser::parse(r#"
+        Q -> @RULE_1
+        A -> 'B [latch]
+        @RULE_1
+        "#.as_bytes()).unwrap();
+        assert_eq!(run(&rules_list[""], &parse_chord("Q + A").unwrap()), "\
+@  Q + A
+1. Q -> @RULE_1
+   consumed: Q
+   produced: @RULE_1
+2. A -> 'B [latch]
+   consumed: A
+   produced: 'B
+output: @RULE_1 + 'B
+");
+        assert_eq!(parse_chord("A + 'B").unwrap_err().code(), "E101");
+        assert!(parse_chord("A + NOSUCHKEY").is_err());
+    }
+}
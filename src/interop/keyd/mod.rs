@@ -0,0 +1,416 @@
+//! keydの設定ファイル(.conf)を読み込む。
+//!
+//! ```text
+//! [main]
+//! capslock = layer(nav)     # 押している間だけnavを使う
+//! insert = toggle(num)      # numに切り替える
+//! a = C-b
+//!
+//! [nav]
+//! h = left
+//!
+//! [control]                 # Ctrlを押している間
+//! j = down
+//! ```
+//!
+//! 押している間だけ使うレイヤーと修飾キーのレイヤーは、キーの組み合わせのルール
+//! に、toggleで切り替えるレイヤーは別のルール名に変換する。
+//!
+//! 書き出すときは、修飾キーとの組み合わせを"C-b"のように出力できる。
+
+use std::collections::HashMap;
+
+use key_converter::rules::key_rule::Key;
+use key_converter::rules::key_rule::KeyRule;
+use key_converter::rules::key_rule::keycode::*;
+use message::Error;
+use message::Msg;
+use super::Imported;
+use super::Remap;
+use super::SetsBuilder;
+use super::Target;
+use super::parse_chord;
+
+
+/// セクションとその中の"キー = 動作"のリスト
+struct Section {
+    name: String,
+    line: usize,
+    bindings: Vec<(usize, String, String)>,
+}
+
+/// レイヤーを使う方法
+enum Activation {
+    Hold(Vec<Vec<u16>>), // 押している間使う。修飾キーのレイヤーは左右のキー
+    Toggle,
+}
+
+/// keydのキーの名前からキーコードを返す
+fn key_code(name: &str) -> Option<u16> {
+    let code = match name {
+        "control" | "leftcontrol" => KEY_LEFTCTRL,
+        "rightcontrol" => KEY_RIGHTCTRL,
+        "shift" => KEY_LEFTSHIFT,
+        "alt" => KEY_LEFTALT,
+        "meta" => KEY_LEFTMETA,
+        "altgr" => KEY_RIGHTALT,
+        "escape" => KEY_ESC,
+        "-" => KEY_MINUS,
+        "=" => KEY_EQUAL,
+        "[" => KEY_LEFTBRACE,
+        "]" => KEY_RIGHTBRACE,
+        "\\" => KEY_BACKSLASH,
+        ";" => KEY_SEMICOLON,
+        "'" => KEY_APOSTROPHE,
+        "`" => KEY_GRAVE,
+        "," => KEY_COMMA,
+        "." => KEY_DOT,
+        "/" => KEY_SLASH,
+        _ => return Keycode::new().from_keyword(name)
+    };
+    Some(code)
+}
+
+/// キーコードからkeydのキーの名前を返す。key_codeの逆
+fn key_name(keycode: &Keycode, code: u16) -> Result<String, Error> {
+    match code {
+        KEY_LEFTCTRL => Ok("leftcontrol".to_string()),
+        KEY_RIGHTCTRL => Ok("rightcontrol".to_string()),
+        _ => keycode.from_keycode(code).map(|n| n.to_lowercase())
+            .ok_or_else(|| Msg::ExportUnknownKey(code.to_string(), Target::Keyd.name()).into())
+    }
+}
+
+/// "C-b"のような書き方で使う修飾キーの名前を返す
+fn modifier_name(code: u16) -> Option<&'static str> {
+    match code {
+        KEY_LEFTCTRL | KEY_RIGHTCTRL => Some("C"),
+        KEY_LEFTSHIFT | KEY_RIGHTSHIFT => Some("S"),
+        KEY_LEFTALT => Some("A"),
+        KEY_LEFTMETA | KEY_RIGHTMETA => Some("M"),
+        KEY_RIGHTALT => Some("G"),
+        _ => None
+    }
+}
+
+/// 修飾キーのレイヤーの名前から、そのレイヤーを使うキーを返す
+fn modifier_layer(name: &str) -> Option<Vec<u16>> {
+    match name {
+        "control" => Some(vec![KEY_LEFTCTRL, KEY_RIGHTCTRL]),
+        "shift" => Some(vec![KEY_LEFTSHIFT, KEY_RIGHTSHIFT]),
+        "alt" => Some(vec![KEY_LEFTALT]),
+        "meta" => Some(vec![KEY_LEFTMETA, KEY_RIGHTMETA]),
+        "altgr" => Some(vec![KEY_RIGHTALT]),
+        _ => None
+    }
+}
+
+/// "name(arg)"の形の動作を(name, arg)に分ける
+fn split_call(s: &str) -> Option<(&str, &str)> {
+    let open = s.find('(')?;
+    if !s.ends_with(')') {
+        return None
+    }
+    Some((&s[..open], s[open+1..s.len()-1].trim()))
+}
+
+fn parse_sections(s: &str) -> Vec<Section> {
+    let mut sections: Vec<Section> = Vec::new();
+
+    for (i, l) in s.lines().enumerate() {
+        let l = l.trim();
+        if l.is_empty() || l.starts_with('#') {
+            continue
+        }
+
+        if l.starts_with('[') && l.ends_with(']') {
+            sections.push(Section {
+                name: l[1..l.len()-1].trim().to_string(),
+                line: i + 1,
+                bindings: Vec::new(),
+            });
+            continue
+        }
+
+        // "= = x"のように"="キーを書けるので、" = "を優先する
+        let split = l.find(" = ").map(|p| (p, p + 3))
+            .or_else(|| l.find('=').map(|p| (p, p + 1)));
+        let (lhs, rhs) = match split {
+            Some((a, b)) => (l[..a].trim(), l[b..].trim()),
+            None => (l, "")
+        };
+
+        match sections.last_mut() {
+            Some(section) => section.bindings.push((i + 1, lhs.to_string(), rhs.to_string())),
+            None => sections.push(Section {
+                name: String::new(),
+                line: i + 1,
+                bindings: vec![(i + 1, lhs.to_string(), rhs.to_string())],
+            })
+        }
+    }
+
+    sections
+}
+
+/// "a+b"のようなキーのリストを変換する
+fn parse_keys(s: &str) -> Option<Vec<u16>> {
+    s.split('+').map(|k| key_code(k.trim())).collect()
+}
+
+pub fn import(s: &str) -> Result<Imported, Error> {
+    let sections = parse_sections(s);
+    let mut builder = SetsBuilder::new();
+    let mut activations: HashMap<String, Activation> = HashMap::new();
+
+    // mainの中のレイヤーを使うキーを先に集める
+    for section in sections.iter().filter(|s| s.name == "main") {
+        for (line, lhs, rhs) in &section.bindings {
+            let (name, arg) = match split_call(rhs) {
+                Some(c) => c,
+                None => continue
+            };
+            let keys = match parse_keys(lhs) {
+                Some(k) => k,
+                None => continue
+            };
+
+            match name {
+                "layer" => match activations.entry(arg.to_string())
+                    .or_insert_with(|| Activation::Hold(Vec::new()))
+                {
+                    Activation::Hold(triggers) => triggers.push(keys),
+                    Activation::Toggle => builder.warn(*line, Msg::ImportUnsupported(rhs.clone()))
+                },
+                "toggle" => {
+                    activations.insert(arg.to_string(), Activation::Toggle);
+                    builder.set(arg, Some(""));
+                },
+                _ => ()
+            }
+        }
+    }
+
+    for section in &sections {
+        match section.name.as_str() {
+            // どのデバイスに適用するかは関係ない
+            "ids" => continue,
+            "main" => (),
+            "" | "global" => {
+                for (line, lhs, _) in &section.bindings {
+                    builder.warn(*line, Msg::ImportIgnored(lhs.clone()));
+                }
+                continue
+            },
+            _ => ()
+        }
+
+        // "nav:C"のようにレイヤーに修飾キーを付けることはできない
+        let mut name = section.name.as_str();
+        if let Some(p) = name.find(':') {
+            builder.warn(section.line, Msg::ImportUnsupported(section.name.clone()));
+            name = &name[..p];
+        }
+
+        // ルールの左側に追加するキーの組み合わせのリスト
+        let mut prefixes: Vec<Vec<u16>> = vec![Vec::new()];
+        let mut target = "";
+        if name != "main" {
+            for part in name.split('+') {
+                let keys = match (modifier_layer(part), activations.get(part)) {
+                    (Some(m), _) => m.into_iter().map(|k| vec![k]).collect(),
+                    (None, Some(Activation::Hold(triggers))) => triggers.clone(),
+                    (None, Some(Activation::Toggle)) if !name.contains('+') => {
+                        target = name;
+                        vec![Vec::new()]
+                    },
+                    _ => Vec::new()
+                };
+
+                prefixes = prefixes.iter()
+                    .flat_map(|p| keys.iter().map(move |k| {
+                        let mut p = p.clone();
+                        p.extend(k);
+                        p
+                    }))
+                    .collect();
+            }
+
+            // 使われることのないレイヤー
+            if prefixes.is_empty() {
+                builder.warn(section.line, Msg::ImportUnsupported(section.name.clone()));
+                continue
+            }
+        }
+
+        for (line, lhs, rhs) in &section.bindings {
+            let keys = match parse_keys(lhs) {
+                Some(k) => k,
+                None => {
+                    builder.warn(*line, Msg::ImportUnknownKey(lhs.clone()));
+                    continue
+                }
+            };
+
+            let v = match split_call(rhs) {
+                Some(("layer", _)) if name == "main" => continue,
+                Some(("toggle", arg)) => vec![Key::Rule(arg.to_string())],
+                Some(_) => {
+                    builder.warn(*line, Msg::ImportUnsupported(rhs.clone()));
+                    continue
+                },
+                None if rhs == "noop" => Vec::new(),
+                None => match parse_chord(rhs, key_code) {
+                    Some(v) => v,
+                    None => {
+                        builder.warn(*line, Msg::ImportUnsupported(rhs.clone()));
+                        continue
+                    }
+                }
+            };
+
+            // 切り替えたレイヤーで同じキーを押すと元に戻す
+            let v = match &v[..] {
+                [Key::Rule(r)] if r == target => vec![Key::Rule(String::new())],
+                _ => v
+            };
+
+            for prefix in &prefixes {
+                let k = prefix.iter().chain(keys.iter()).map(|k| Key::Raw(*k)).collect();
+                let rule = KeyRule::new(k, v.clone());
+                if rule.k.len() == 1 && rule.v == vec![Key::Con(keys[0])] {
+                    continue
+                }
+                builder.push(target, *line, rule);
+            }
+        }
+    }
+
+    // 押している間だけ使うレイヤーのキーは、単独で押すとそのまま入力される
+    for section in sections.iter().filter(|s| s.name == "main") {
+        for (line, lhs, rhs) in &section.bindings {
+            if let Some(("layer", _)) = split_call(rhs) {
+                builder.warn(*line, Msg::ImportLayerKeyLeaks(lhs.clone()));
+            }
+        }
+    }
+
+    Ok(builder.build())
+}
+
+/// キーの置き換えをkeydの設定ファイルにする
+pub fn export(remaps: &[Remap]) -> Result<String, Error> {
+    let keycode = Keycode::new();
+    let mut s = String::from("# generated by keymap export\n[ids]\n*\n\n[main]\n");
+
+    for r in remaps {
+        // 修飾キーを押した後に最後のキーを押すものだけを変換できる
+        let to = match r.to.split_last() {
+            Some((last, modifiers)) => {
+                let mut to = String::new();
+                for m in modifiers {
+                    let name = modifier_name(*m).ok_or_else(||
+                        Error::new(Msg::ExportMultipleKeys(r.source.clone(), Target::Keyd.name())))?;
+                    to += name;
+                    to += "-";
+                }
+                to + &key_name(&keycode, *last)?
+            },
+            None => "noop".to_string()
+        };
+
+        s += &format!("{} = {}\n", key_name(&keycode, r.from)?, to);
+    }
+
+    Ok(s)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::import;
+    use interop::export;
+    use interop::Target;
+    use key_converter::rules::RulesParser;
+    use key_converter::rules::syntax::SyntaxTree;
+
+    #[test]
+    fn test_import() {
+        let imported = import(r#"
+        [ids]
+        *
+
+        [main]
+        capslock = layer(nav)
+        insert = toggle(num)
+        a = C-b
+        esc = overload(control, esc)
+        leftmeta = noop
+
+        [nav]
+        h = left
+
+        [control]
+        j = down
+
+        [num]
+        j = 1
+        insert = toggle(num)
+        "#).unwrap();
+
+        assert_eq!(SyntaxTree::from_sets(&imported.sets).format(), "\
+INSERT        -> @num
+A             -> 'LEFTCTRL + 'B
+LEFTMETA      -> _
+CAPSLOCK + H  -> 'LEFT
+LEFTCTRL + J  -> 'DOWN
+RIGHTCTRL + J -> 'DOWN
+
+@num : @
+  J      -> '1
+  INSERT -!> @
+");
+        let lines: Vec<usize> = imported.warnings.iter().map(|w| w.line).collect();
+        assert_eq!(lines, vec![9, 6]);
+    }
+
+    #[test]
+    fn test_import_layer_before_main() {
+        // レイヤーがmainより前にあっても、mainと同じキーは上書きにする
+        let imported = import(r#"
+        [num]
+        j = 1
+
+        [main]
+        insert = toggle(num)
+        j = down
+        "#).unwrap();
+
+        let s = SyntaxTree::from_sets(&imported.sets).format();
+        assert_eq!(s, "\
+INSERT -> @num
+J      -> 'DOWN
+
+@num : @
+  J -!> '1
+");
+        assert!(RulesParser::parse(s.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_export() {
+        let rules = RulesParser::parse("CAPSLOCK -> 'LEFTCTRL\nA -> 'LEFTCTRL + 'B\nINSERT -> _".as_bytes()).unwrap();
+        assert_eq!(export(Target::Keyd, &rules[""]).unwrap(), "\
+# generated by keymap export
+[ids]
+*
+
+[main]
+a = C-b
+capslock = leftcontrol
+insert = noop
+");
+        let rules = RulesParser::parse("A -> 'B + 'C".as_bytes()).unwrap();
+        assert_eq!(export(Target::Keyd, &rules[""]).unwrap_err().code(), "E123");
+    }
+}
@@ -0,0 +1,368 @@
+//! KMonadの設定ファイル(.kbd)を読み込む。
+//!
+//! ```text
+//! (defsrc caps a    s)
+//! (defalias nav (layer-toggle nav))
+//! (deflayer base @nav C-a s)
+//! (deflayer nav  _    left right)
+//! ```
+//!
+//! 最初のdeflayerを""のルールにする。layer-toggleで押している間だけ使う
+//! レイヤーはキーの組み合わせのルールに、layer-switchで切り替えるレイヤーは
+//! 別のルール名に変換する。
+
+use std::collections::HashMap;
+
+use key_converter::rules::key_rule::Key;
+use key_converter::rules::key_rule::KeyRule;
+use key_converter::rules::key_rule::keycode::*;
+use message::Error;
+use message::Msg;
+use super::Imported;
+use super::SetsBuilder;
+use super::parse_chord;
+use super::remap;
+
+
+/// S式
+#[derive(Debug, Clone, PartialEq)]
+enum Sexp {
+    Atom(String, usize), // 文字列と行番号
+    List(Vec<Sexp>, usize),
+}
+
+impl Sexp {
+    fn line(&self) -> usize {
+        match self {
+            Sexp::Atom(_, l) | Sexp::List(_, l) => *l
+        }
+    }
+
+    fn atom(&self) -> Option<&str> {
+        match self {
+            Sexp::Atom(s, _) => Some(s),
+            _ => None
+        }
+    }
+
+    /// 元の文字列に近い形に戻す。警告に使う
+    fn to_source(&self) -> String {
+        match self {
+            Sexp::Atom(s, _) => s.clone(),
+            Sexp::List(l, _) => format!("({})", l.iter()
+                .map(|s| s.to_source()).collect::<Vec<String>>().join(" "))
+        }
+    }
+}
+
+/// 文字列をS式のリストにする
+fn parse_sexps(s: &str) -> Result<Vec<Sexp>, Error> {
+    // (開いた位置の行番号, 中身)のスタック
+    let mut stack: Vec<(usize, Vec<Sexp>)> = vec![(0, Vec::new())];
+    let mut chars = s.chars().peekable();
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => (),
+            // ;;から行末まではコメント
+            ';' if chars.peek() == Some(&';') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break
+                    }
+                }
+            },
+            // #| |#はブロックコメント
+            '#' if chars.peek() == Some(&'|') => {
+                let mut last = ' ';
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    if last == '|' && c == '#' {
+                        break
+                    }
+                    last = c;
+                }
+            },
+            '(' => stack.push((line, Vec::new())),
+            ')' => {
+                let (start, list) = stack.pop().unwrap();
+                match stack.last_mut() {
+                    Some((_, parent)) => parent.push(Sexp::List(list, start)),
+                    None => return Err(Error::new(Msg::ImportSyntax(")".to_string())).with_line(line))
+                }
+            },
+            '"' => {
+                let mut atom = String::new();
+                for c in chars.by_ref() {
+                    if c == '"' {
+                        break
+                    }
+                    atom.push(c);
+                }
+                stack.last_mut().unwrap().1.push(Sexp::Atom(atom, line));
+            },
+            _ => {
+                let mut atom = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' {
+                        break
+                    }
+                    atom.push(c);
+                    chars.next();
+                }
+                stack.last_mut().unwrap().1.push(Sexp::Atom(atom, line));
+            }
+        }
+    }
+
+    if stack.len() != 1 {
+        let (start, _) = stack.pop().unwrap();
+        return Err(Error::new(Msg::ImportSyntax("(".to_string())).with_line(start))
+    }
+
+    Ok(stack.pop().unwrap().1)
+}
+
+/// KMonadのキーの名前からキーコードを返す
+fn key_code(name: &str) -> Option<u16> {
+    let code = match name {
+        "lctl" => KEY_LEFTCTRL,
+        "rctl" => KEY_RIGHTCTRL,
+        "lsft" => KEY_LEFTSHIFT,
+        "rsft" => KEY_RIGHTSHIFT,
+        "lalt" => KEY_LEFTALT,
+        "ralt" => KEY_RIGHTALT,
+        "lmet" => KEY_LEFTMETA,
+        "rmet" => KEY_RIGHTMETA,
+        "caps" => KEY_CAPSLOCK,
+        "spc" => KEY_SPACE,
+        "ret" | "ent" => KEY_ENTER,
+        "bspc" => KEY_BACKSPACE,
+        "grv" => KEY_GRAVE,
+        "min" => KEY_MINUS,
+        "eql" => KEY_EQUAL,
+        "lbrc" => KEY_LEFTBRACE,
+        "rbrc" => KEY_RIGHTBRACE,
+        "bsls" => KEY_BACKSLASH,
+        "scln" => KEY_SEMICOLON,
+        "quot" => KEY_APOSTROPHE,
+        "comm" => KEY_COMMA,
+        "slsh" => KEY_SLASH,
+        "del" => KEY_DELETE,
+        "ins" => KEY_INSERT,
+        "pgup" => KEY_PAGEUP,
+        "pgdn" => KEY_PAGEDOWN,
+        "rght" => KEY_RIGHT,
+        "prnt" => KEY_SYSRQ,
+        "cmp" => KEY_COMPOSE,
+        "nlck" => KEY_NUMLOCK,
+        "slck" => KEY_SCROLLLOCK,
+        _ => return Keycode::new().from_keyword(name)
+    };
+    Some(code)
+}
+
+/// deflayerの中の一つのキーの動作
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    Transparent,
+    Keys(Vec<Key>),
+    Hold(String),   // layer-toggle
+    Switch(String), // layer-switch
+    Unsupported(String),
+}
+
+fn resolve(item: &Sexp, aliases: &HashMap<String, Sexp>, depth: usize) -> Action {
+    match item {
+        Sexp::Atom(s, _) if s == "_" => Action::Transparent,
+        Sexp::Atom(s, _) if s == "XX" => Action::Keys(Vec::new()),
+        Sexp::Atom(s, _) if s.starts_with('@') && depth < 8 => {
+            match aliases.get(&s[1..]) {
+                Some(a) => resolve(a, aliases, depth + 1),
+                None => Action::Unsupported(s.clone())
+            }
+        },
+        Sexp::Atom(s, _) => match parse_chord(s, key_code) {
+            Some(keys) => Action::Keys(keys),
+            None => Action::Unsupported(s.clone())
+        },
+        Sexp::List(l, _) => {
+            let arg = l.get(1).and_then(|a| a.atom()).map(|a| a.to_string());
+            match (l.first().and_then(|f| f.atom()), arg) {
+                (Some("layer-toggle"), Some(a)) | (Some("layer-while-held"), Some(a)) => Action::Hold(a),
+                (Some("layer-switch"), Some(a)) => Action::Switch(a),
+                _ => Action::Unsupported(item.to_source())
+            }
+        }
+    }
+}
+
+pub fn import(s: &str) -> Result<Imported, Error> {
+    let mut builder = SetsBuilder::new();
+    let mut src: Vec<(usize, Option<u16>)> = Vec::new();
+    let mut aliases = HashMap::new();
+    let mut layers: Vec<(String, usize, Vec<Sexp>)> = Vec::new();
+
+    for form in parse_sexps(s)? {
+        let list = match &form {
+            Sexp::List(l, _) if !l.is_empty() => l,
+            _ => {
+                builder.warn(form.line(), Msg::ImportUnsupported(form.to_source()));
+                continue
+            }
+        };
+
+        match list[0].atom() {
+            Some("defcfg") => builder.warn(form.line(), Msg::ImportIgnored("defcfg".to_string())),
+            Some("defsrc") => {
+                src = list[1..].iter().map(|k| (k.line(), k.atom().and_then(key_code))).collect();
+                for k in &list[1..] {
+                    if k.atom().and_then(key_code).is_none() {
+                        builder.warn(k.line(), Msg::ImportUnknownKey(k.to_source()));
+                    }
+                }
+            },
+            Some("defalias") => {
+                for pair in list[1..].chunks(2) {
+                    if let (Some(name), Some(value)) = (pair[0].atom(), pair.get(1)) {
+                        aliases.insert(name.to_string(), value.clone());
+                    }
+                }
+            },
+            Some("deflayer") => match list.get(1).and_then(|n| n.atom()) {
+                Some(name) => layers.push((name.to_string(), form.line(), list[2..].to_vec())),
+                None => builder.warn(form.line(), Msg::ImportUnsupported(form.to_source()))
+            },
+            _ => builder.warn(form.line(), Msg::ImportUnsupported(form.to_source()))
+        }
+    }
+
+    // 最初のレイヤーが""のルールになる
+    let base = match layers.first() {
+        Some((name, _, _)) => name.clone(),
+        None => return Ok(builder.build())
+    };
+    let rule_name = |layer: &str| if layer == base { String::new() } else { layer.to_string() };
+
+    // レイヤーを使うキーを集める
+    let mut holds: HashMap<String, Vec<u16>> = HashMap::new();
+    let mut switches: Vec<String> = Vec::new();
+    for (_, _, items) in &layers {
+        for (item, (_, code)) in items.iter().zip(src.iter()) {
+            match (resolve(item, &aliases, 0), code) {
+                (Action::Hold(l), Some(c)) => holds.entry(l).or_default().push(*c),
+                (Action::Switch(l), _) => switches.push(l),
+                _ => ()
+            }
+        }
+    }
+
+    for (name, line, items) in &layers {
+        if items.len() != src.len() {
+            builder.warn(*line, Msg::ImportSyntax(format!("deflayer {}", name)));
+        }
+
+        // 押している間だけ使うレイヤーのときは、そのキーを組み合わせる
+        let mut prefixes: Vec<Option<u16>> = Vec::new();
+        if name == &base || switches.contains(name) {
+            prefixes.push(None);
+        }
+        if name != &base {
+            if let Some(h) = holds.get(name) {
+                prefixes.extend(h.iter().map(|k| Some(*k)));
+            }
+        }
+        if prefixes.is_empty() {
+            builder.warn(*line, Msg::ImportUnsupported(format!("deflayer {}", name)));
+            continue
+        }
+
+        for (item, (_, code)) in items.iter().zip(src.iter()) {
+            let code = match code {
+                Some(c) => *c,
+                None => continue
+            };
+
+            let v = match resolve(item, &aliases, 0) {
+                Action::Transparent | Action::Hold(_) => continue,
+                Action::Keys(v) => v,
+                Action::Switch(l) => vec![Key::Rule(rule_name(&l))],
+                Action::Unsupported(_) => {
+                    builder.warn(item.line(), Msg::ImportUnsupported(item.to_source()));
+                    continue
+                },
+            };
+
+            for prefix in &prefixes {
+                let rule = match prefix {
+                    None => remap(code, v.clone()),
+                    Some(p) if *p == code => continue,
+                    Some(p) => Some(KeyRule::new(vec![Key::Raw(*p), Key::Raw(code)], v.clone())),
+                };
+                let set = if prefix.is_none() { rule_name(name) } else { String::new() };
+                if let Some(rule) = rule {
+                    builder.push(&set, item.line(), rule);
+                }
+            }
+        }
+    }
+
+    // 押している間だけ使うレイヤーのキーは、単独で押すとそのまま入力される
+    for (l, keys) in &holds {
+        for k in keys {
+            let name = Keycode::new().from_keycode(*k).unwrap_or_default();
+            builder.warn(0, Msg::ImportLayerKeyLeaks(format!("{} (layer-toggle {})", name, l)));
+        }
+    }
+
+    Ok(builder.build())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::import;
+    use super::parse_sexps;
+    use key_converter::rules::syntax::SyntaxTree;
+
+    #[test]
+    fn test_parse_sexps() {
+        let s = parse_sexps("(a (b c)) ;; comment\n#| block\n|# (d \"e f\")").unwrap();
+        assert_eq!(s.len(), 2);
+        assert_eq!(s[1].to_source(), "(d e f)");
+        assert_eq!(parse_sexps("(a\n(b)").unwrap_err().line, Some(1));
+    }
+
+    #[test]
+    fn test_import() {
+        let imported = import(r#"
+        (defcfg input (device-file "/dev/input/event0"))
+        (defsrc caps a    s     d)
+        (defalias nav (layer-toggle nav)
+                  sym (layer-switch sym))
+        (deflayer base @nav C-a  s     @sym)
+        (deflayer nav  _    left right XX)
+        (deflayer sym  _    1    2     (layer-switch base))
+        "#).unwrap();
+
+        assert_eq!(SyntaxTree::from_sets(&imported.sets).format(), "\
+A            -> 'LEFTCTRL + 'A
+D            -> @sym
+CAPSLOCK + A -> 'LEFT
+CAPSLOCK + S -> 'RIGHT
+CAPSLOCK + D -> _
+
+@sym
+  A -> '1
+  S -> '2
+  D -> @
+");
+        let lines: Vec<usize> = imported.warnings.iter().map(|w| w.line).collect();
+        assert_eq!(lines, vec![2, 0]);
+    }
+}
@@ -0,0 +1,421 @@
+//! 他のツールの設定ファイルとルールを変換する。
+//! 読み込みで変換できるのは単純なキーの置き換え、レイヤー、修飾キーとの組み合わせ
+//! のみで、それ以外は警告を出して読み飛ばす。
+//! 書き出しは1つのキーを置き換えるルールのみで、それ以外はエラーにする。
+
+use key_converter::rules::Rules;
+use key_converter::rules::key_rule::Key;
+use key_converter::rules::key_rule::KeyRule;
+use key_converter::rules::key_rule::keycode::*;
+use message::Error;
+use message::Msg;
+
+pub mod keyd;
+pub mod xmodmap;
+pub mod kmonad;
+pub mod hwdb;
+pub mod xkb;
+
+
+/// 変換元のツール
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    Keyd,
+    Xmodmap,
+    Kmonad,
+}
+
+impl Tool {
+    pub fn from_str(s: &str) -> Option<Tool> {
+        match s.to_lowercase().as_str() {
+            "keyd" => Some(Tool::Keyd),
+            "xmodmap" => Some(Tool::Xmodmap),
+            "kmonad" => Some(Tool::Kmonad),
+            _ => None
+        }
+    }
+}
+
+/// 変換できなかった部分の警告
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub line: usize, // 1から始まる行番号
+    pub msg: Msg,
+}
+
+/// 変換した結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct Imported {
+    pub sets: Vec<Rules>,
+    pub warnings: Vec<Warning>,
+}
+
+/// 設定ファイルを読み込み、ルールに変換する
+pub fn import(tool: Tool, s: &str) -> Result<Imported, Error> {
+    match tool {
+        Tool::Keyd => keyd::import(s),
+        Tool::Xmodmap => xmodmap::import(s),
+        Tool::Kmonad => kmonad::import(s),
+    }
+}
+
+/// 変換先のツール
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Keyd,
+    Hwdb,
+    Xkb,
+}
+
+impl Target {
+    pub fn from_str(s: &str) -> Option<Target> {
+        match s.to_lowercase().as_str() {
+            "keyd" => Some(Target::Keyd),
+            "hwdb" => Some(Target::Hwdb),
+            "xkb" => Some(Target::Xkb),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Target::Keyd => "keyd",
+            Target::Hwdb => "hwdb",
+            Target::Xkb => "XKB",
+        }
+    }
+}
+
+/// ルールを他のツールの設定ファイルに変換する
+pub fn export(target: Target, rules: &Rules) -> Result<String, Error> {
+    let result = remaps(rules).and_then(|remaps| match target {
+        Target::Keyd => keyd::export(&remaps),
+        Target::Hwdb => hwdb::export(&remaps),
+        Target::Xkb => xkb::export(&remaps),
+    });
+
+    match rules.get_name() {
+        "" => result,
+        name => result.map_err(|e| e.with_rule(name))
+    }
+}
+
+/// 1つのキーの置き換え。変換後のキーはtoの順に押される
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Remap {
+    pub from: u16,
+    pub to: Vec<u16>,
+    pub source: String, // エラーを表示するためのルールの文字列
+}
+
+impl Remap {
+    /// 変換後のキーが1つのときはそのキーを、何も入力しないときはNoneを返す。
+    /// そうでなければ、targetでは変換できないというエラーにする
+    fn single(&self, target: Target) -> Result<Option<u16>, Error> {
+        match self.to[..] {
+            [to] => Ok(Some(to)),
+            [] => Ok(None),
+            _ => Err(Msg::ExportMultipleKeys(self.source.clone(), target.name()).into())
+        }
+    }
+}
+
+/// ルールを1つのキーの置き換えのリストにする。
+/// キーの組み合わせ、ルールの切り替え、他のルールと連続した変換は
+/// keymapでなければ扱えないのでエラーにする
+fn remaps(rules: &Rules) -> Result<Vec<Remap>, Error> {
+    let mut remaps = Vec::new();
+    for rule in rules.get_list() {
+        let source = rule.to_source();
+        let from = match rule.k[..] {
+            [Key::Raw(k)] => k,
+            [Key::Con(_)] | [Key::Var(_)] => return Err(Msg::ExportChained(source).into()),
+            _ => return Err(Msg::ExportChord(source).into())
+        };
+
+        let mut to = Vec::new();
+        for v in &rule.v {
+            match v {
+                Key::Con(k) => to.push(*k),
+                Key::Raw(_) | Key::Var(_) => return Err(Msg::ExportChained(source).into()),
+                Key::Rule(_) => return Err(Msg::ExportRuleSwitch(source).into()),
+            }
+        }
+
+        remaps.push(Remap { from, to, source });
+    }
+
+    remaps.sort_by_key(|r| r.from);
+    Ok(remaps)
+}
+
+/// ルール名ごとにルールを集める。
+/// 追加した順番を保ち、同じキーのルールは後から追加したものを捨てる
+struct SetsBuilder {
+    sets: Vec<(String, Option<String>, Vec<KeyRule>)>,
+    warnings: Vec<Warning>,
+}
+
+impl SetsBuilder {
+    fn new() -> SetsBuilder {
+        SetsBuilder {
+            sets: vec![(String::new(), None, Vec::new())],
+            warnings: Vec::new(),
+        }
+    }
+
+    fn set(&mut self, name: &str, extend: Option<&str>) -> usize {
+        match self.sets.iter().position(|(n, _, _)| n == name) {
+            Some(i) => i,
+            None => {
+                self.sets.push((name.to_string(), extend.map(|e| e.to_string()), Vec::new()));
+                self.sets.len() - 1
+            }
+        }
+    }
+
+    /// ルールを追加する。上書きにするかどうかはbuildで決める
+    fn push(&mut self, name: &str, line: usize, rule: KeyRule) {
+        let i = self.set(name, None);
+        if self.sets[i].2.iter().any(|r| r.compare_k(&rule.k)) {
+            self.warn(line, Msg::ImportDuplicate(rule.to_source()));
+            return
+        }
+        self.sets[i].2.push(rule);
+    }
+
+    /// 継承元を近い順にたどる。循環していれば一周したところで止める
+    fn ancestors(&self, i: usize) -> Vec<usize> {
+        let mut chain = Vec::new();
+        let mut extend = self.sets[i].1.as_deref();
+        while let Some(e) = extend {
+            match self.sets.iter().position(|(n, _, _)| n == e) {
+                Some(j) if j != i && !chain.contains(&j) => {
+                    chain.push(j);
+                    extend = self.sets[j].1.as_deref();
+                },
+                _ => break
+            }
+        }
+        chain
+    }
+
+    fn warn(&mut self, line: usize, msg: Msg) {
+        self.warnings.push(Warning { line, msg });
+    }
+
+    /// すべてのルールを集めてから、継承元のどれかに同じキーのルールがあるものを
+    /// 上書きにする。セクションの順番によらないようにするため
+    fn build(mut self) -> Imported {
+        for i in 0..self.sets.len() {
+            let ancestors = self.ancestors(i);
+            for k in 0..self.sets[i].2.len() {
+                let rule = &self.sets[i].2[k];
+                let ove = ancestors.iter().any(|j| self.sets[*j].2.iter()
+                    .any(|r| r.compare_k(&rule.k) && r.ord == rule.ord));
+                self.sets[i].2[k].ove = ove;
+            }
+        }
+
+        Imported {
+            sets: self.sets.into_iter()
+                .map(|(name, extend, list)| Rules::from_vec(&name, extend, list))
+                .collect(),
+            warnings: self.warnings,
+        }
+    }
+}
+
+
+/// "C-a"のような書き方で使う修飾キーの名前からキーコードを返す。
+/// keydとKMonadで使う
+fn modifier_code(s: &str) -> Option<u16> {
+    match s {
+        "C" => Some(KEY_LEFTCTRL),
+        "S" => Some(KEY_LEFTSHIFT),
+        "A" => Some(KEY_LEFTALT),
+        "M" => Some(KEY_LEFTMETA),
+        "G" | "RA" => Some(KEY_RIGHTALT),
+        "RC" => Some(KEY_RIGHTCTRL),
+        "RS" => Some(KEY_RIGHTSHIFT),
+        "RM" => Some(KEY_RIGHTMETA),
+        _ => None
+    }
+}
+
+/// "C-S-a"のような修飾キー付きのキーを、変換後のキーのリストにする。
+/// keyは修飾キーを除いたキーの名前をキーコードにする関数
+fn parse_chord<F>(s: &str, key: F) -> Option<Vec<Key>>
+    where F: Fn(&str) -> Option<u16>
+{
+    let mut keys = Vec::new();
+    let mut rest = s;
+
+    while let Some(i) = rest.find('-') {
+        // "-"のみ、または末尾が"-"のときはキーの名前
+        if i == 0 || i == rest.len() - 1 {
+            break
+        }
+        keys.push(Key::Con(modifier_code(&rest[..i])?));
+        rest = &rest[i+1..];
+    }
+
+    keys.push(Key::Con(key(rest)?));
+    Some(keys)
+}
+
+/// キーの変換を作る。同じキーへの変換はNoneを返す
+fn remap(from: u16, to: Vec<Key>) -> Option<KeyRule> {
+    if to == vec![Key::Con(from)] {
+        return None
+    }
+    Some(KeyRule::new(vec![Key::Raw(from)], to))
+}
+
+
+/// X11のkeysymの名前と、USキーボードでそのkeysymを入力するキー。
+/// boolはShiftが必要かどうか
+const KEYSYMS: &[(&str, u16, bool)] = &[
+    ("Escape", KEY_ESC, false), ("Return", KEY_ENTER, false),
+    ("BackSpace", KEY_BACKSPACE, false), ("Tab", KEY_TAB, false),
+    ("space", KEY_SPACE, false), ("Delete", KEY_DELETE, false),
+    ("Insert", KEY_INSERT, false), ("Home", KEY_HOME, false),
+    ("End", KEY_END, false), ("Prior", KEY_PAGEUP, false),
+    ("Page_Up", KEY_PAGEUP, false), ("Next", KEY_PAGEDOWN, false),
+    ("Page_Down", KEY_PAGEDOWN, false), ("Left", KEY_LEFT, false),
+    ("Right", KEY_RIGHT, false), ("Up", KEY_UP, false),
+    ("Down", KEY_DOWN, false), ("Print", KEY_SYSRQ, false),
+    ("Pause", KEY_PAUSE, false), ("Menu", KEY_COMPOSE, false),
+    ("Control_L", KEY_LEFTCTRL, false), ("Control_R", KEY_RIGHTCTRL, false),
+    ("Shift_L", KEY_LEFTSHIFT, false), ("Shift_R", KEY_RIGHTSHIFT, false),
+    ("Alt_L", KEY_LEFTALT, false), ("Alt_R", KEY_RIGHTALT, false),
+    ("ISO_Level3_Shift", KEY_RIGHTALT, false),
+    ("Super_L", KEY_LEFTMETA, false), ("Super_R", KEY_RIGHTMETA, false),
+    ("Caps_Lock", KEY_CAPSLOCK, false), ("Num_Lock", KEY_NUMLOCK, false),
+    ("Scroll_Lock", KEY_SCROLLLOCK, false),
+    ("Muhenkan", KEY_MUHENKAN, false), ("Henkan_Mode", KEY_HENKAN, false),
+    ("Henkan", KEY_HENKAN, false),
+    ("Hiragana_Katakana", KEY_KATAKANAHIRAGANA, false),
+    ("Zenkaku_Hankaku", KEY_ZENKAKUHANKAKU, false),
+    ("minus", KEY_MINUS, false), ("equal", KEY_EQUAL, false),
+    ("bracketleft", KEY_LEFTBRACE, false), ("bracketright", KEY_RIGHTBRACE, false),
+    ("backslash", KEY_BACKSLASH, false), ("semicolon", KEY_SEMICOLON, false),
+    ("apostrophe", KEY_APOSTROPHE, false), ("grave", KEY_GRAVE, false),
+    ("comma", KEY_COMMA, false), ("period", KEY_DOT, false),
+    ("slash", KEY_SLASH, false),
+    ("exclam", KEY_1, true), ("at", KEY_2, true), ("numbersign", KEY_3, true),
+    ("dollar", KEY_4, true), ("percent", KEY_5, true),
+    ("asciicircum", KEY_6, true), ("ampersand", KEY_7, true),
+    ("asterisk", KEY_8, true), ("parenleft", KEY_9, true),
+    ("parenright", KEY_0, true), ("underscore", KEY_MINUS, true),
+    ("plus", KEY_EQUAL, true), ("braceleft", KEY_LEFTBRACE, true),
+    ("braceright", KEY_RIGHTBRACE, true), ("bar", KEY_BACKSLASH, true),
+    ("colon", KEY_SEMICOLON, true), ("quotedbl", KEY_APOSTROPHE, true),
+    ("asciitilde", KEY_GRAVE, true), ("less", KEY_COMMA, true),
+    ("greater", KEY_DOT, true), ("question", KEY_SLASH, true),
+];
+
+/// keysymの名前からキーコードとShiftが必要かどうかを返す
+pub fn keysym_to_code(name: &str) -> Option<(u16, bool)> {
+    if let Some(&(_, code, shift)) = KEYSYMS.iter().find(|(n, _, _)| *n == name) {
+        return Some((code, shift))
+    }
+
+    // 英数字とファンクションキーはキーの名前と同じ
+    let keycode = Keycode::new();
+    let c = name.chars().next()?;
+    if name.len() == 1 && c.is_ascii_alphanumeric() {
+        return keycode.from_keyword(name).map(|k| (k, c.is_ascii_uppercase()))
+    }
+    if c == 'F' && name[1..].parse::<u8>().is_ok() {
+        return keycode.from_keyword(name).map(|k| (k, false))
+    }
+
+    None
+}
+
+/// キーコードから、Shiftなしで入力されるkeysymの名前を返す
+pub fn code_to_keysym(code: u16) -> Option<String> {
+    if let Some((name, _, _)) = KEYSYMS.iter().find(|(_, c, s)| *c == code && !*s) {
+        return Some(name.to_string())
+    }
+
+    let name = Keycode::new().from_keycode(code)?;
+    let c = name.chars().next()?;
+    if name.len() == 1 && c.is_ascii_alphanumeric() {
+        return Some(name.to_lowercase())
+    }
+    if c == 'F' && name[1..].parse::<u8>().is_ok() {
+        return Some(name)
+    }
+
+    None
+}
+
+/// キーコードから、Shiftなしとありで入力されるkeysymの名前を返す
+pub fn code_to_keysyms(code: u16) -> Option<Vec<String>> {
+    let mut syms = vec![code_to_keysym(code)?];
+    if let Some((name, _, _)) = KEYSYMS.iter().find(|(_, c, s)| *c == code && *s) {
+        syms.push(name.to_string());
+    } else if syms[0].len() == 1 && syms[0].chars().all(|c| c.is_ascii_lowercase()) {
+        syms.push(syms[0].to_uppercase());
+    }
+    Some(syms)
+}
+
+/// keysymを変換後のキーのリストにする
+fn keysym_to_keys(name: &str) -> Option<Vec<Key>> {
+    let (code, shift) = keysym_to_code(name)?;
+    if shift {
+        Some(vec![Key::Con(KEY_LEFTSHIFT), Key::Con(code)])
+    } else {
+        Some(vec![Key::Con(code)])
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keysym() {
+        assert_eq!(keysym_to_code("Control_L"), Some((KEY_LEFTCTRL, false)));
+        assert_eq!(keysym_to_code("a"), Some((KEY_A, false)));
+        assert_eq!(keysym_to_code("A"), Some((KEY_A, true)));
+        assert_eq!(keysym_to_code("F12"), Some((KEY_F12, false)));
+        assert_eq!(keysym_to_code("exclam"), Some((KEY_1, true)));
+        assert_eq!(keysym_to_code("Fxx"), None);
+
+        assert_eq!(code_to_keysym(KEY_CAPSLOCK), Some("Caps_Lock".to_string()));
+        assert_eq!(code_to_keysyms(KEY_A), Some(vec!["a".to_string(), "A".to_string()]));
+        assert_eq!(code_to_keysyms(KEY_1), Some(vec!["1".to_string(), "exclam".to_string()]));
+    }
+
+    #[test]
+    fn test_parse_chord() {
+        let k = |s: &str| Keycode::new().from_keyword(s);
+        assert_eq!(parse_chord("a", k), Some(vec![Key::Con(KEY_A)]));
+        assert_eq!(parse_chord("C-S-a", k),
+                   Some(vec![Key::Con(KEY_LEFTCTRL), Key::Con(KEY_LEFTSHIFT), Key::Con(KEY_A)]));
+        assert_eq!(parse_chord("X-a", k), None);
+    }
+
+    #[test]
+    fn test_remaps() {
+        let rules = |s: &str| {
+            let list = s.lines().map(|l| KeyRule::from_str(l).unwrap()).collect();
+            Rules::from_vec("", None, list)
+        };
+
+        let r = remaps(&rules("CAPSLOCK -> 'LEFTCTRL\nA -> 'LEFTCTRL + 'B")).unwrap();
+        assert_eq!(r.iter().map(|r| (r.from, r.to.clone())).collect::<Vec<_>>(),
+                   vec![(KEY_A, vec![KEY_LEFTCTRL, KEY_B]), (KEY_CAPSLOCK, vec![KEY_LEFTCTRL])]);
+
+        assert_eq!(remaps(&rules("A + B -> 'C")).unwrap_err().code(), "E120");
+        assert_eq!(remaps(&rules("A -> @nav")).unwrap_err().code(), "E121");
+        assert_eq!(remaps(&rules("A -> B")).unwrap_err().code(), "E122");
+        assert_eq!(remaps(&rules("'A -> 'B")).unwrap_err().code(), "E122");
+        assert_eq!(export(Target::Hwdb, &rules("A -> 'LEFTCTRL + 'B")).unwrap_err().code(), "E123");
+    }
+}
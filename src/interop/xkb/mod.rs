@@ -0,0 +1,130 @@
+//! XKBのsymbolsファイルに書き出す。
+//!
+//! ```text
+//! partial modifier_keys alphanumeric_keys
+//! xkb_symbols "keymap" {
+//!     replace key <CAPS> { [ Control_L ] };
+//!     modifier_map Control { <CAPS> };
+//! };
+//! ```
+//!
+//! 変換後のキーはUSキーボードのkeysymにする。
+
+use key_converter::rules::key_rule::keycode::*;
+use message::Error;
+use message::Msg;
+use super::Remap;
+use super::Target;
+use super::code_to_keysyms;
+
+
+/// キーコードとXKBのキーの名前
+const XKB_KEYS: &[(u16, &str)] = &[
+    (KEY_ESC, "ESC"), (KEY_GRAVE, "TLDE"), (KEY_BACKSPACE, "BKSP"), (KEY_TAB, "TAB"),
+    (KEY_BACKSLASH, "BKSL"), (KEY_CAPSLOCK, "CAPS"), (KEY_ENTER, "RTRN"),
+    (KEY_LEFTSHIFT, "LFSH"), (KEY_RIGHTSHIFT, "RTSH"), (KEY_LEFTCTRL, "LCTL"),
+    (KEY_RIGHTCTRL, "RCTL"), (KEY_LEFTALT, "LALT"), (KEY_RIGHTALT, "RALT"),
+    (KEY_LEFTMETA, "LWIN"), (KEY_RIGHTMETA, "RWIN"), (KEY_COMPOSE, "COMP"),
+    (KEY_SPACE, "SPCE"), (KEY_102ND, "LSGT"),
+    (KEY_SYSRQ, "PRSC"), (KEY_SCROLLLOCK, "SCLK"), (KEY_PAUSE, "PAUS"),
+    (KEY_INSERT, "INS"), (KEY_HOME, "HOME"), (KEY_PAGEUP, "PGUP"), (KEY_DELETE, "DELE"),
+    (KEY_END, "END"), (KEY_PAGEDOWN, "PGDN"), (KEY_UP, "UP"), (KEY_LEFT, "LEFT"),
+    (KEY_DOWN, "DOWN"), (KEY_RIGHT, "RGHT"), (KEY_NUMLOCK, "NMLK"),
+    (KEY_MUHENKAN, "MUHE"), (KEY_HENKAN, "HENK"), (KEY_KATAKANAHIRAGANA, "HKTG"),
+    (KEY_ZENKAKUHANKAKU, "HZTG"), (KEY_YEN, "AE13"), (KEY_RO, "AB11"),
+];
+
+// 英数字の列の最初のキーコードと、XKBのキーの名前の接頭辞とキーの数
+const XKB_ROWS: &[(u16, &str, u16)] = &[
+    (KEY_1, "AE", 12),
+    (KEY_Q, "AD", 12),
+    (KEY_A, "AC", 11),
+    (KEY_Z, "AB", 10),
+];
+
+fn key_name(code: u16) -> Option<String> {
+    if let Some((_, name)) = XKB_KEYS.iter().find(|(c, _)| *c == code) {
+        return Some(format!("<{}>", name))
+    }
+    for (first, prefix, len) in XKB_ROWS {
+        if code >= *first && code < first + len {
+            return Some(format!("<{}{:02}>", prefix, code - first + 1))
+        }
+    }
+    if (KEY_F1..=KEY_F10).contains(&code) {
+        return Some(format!("<FK{:02}>", code - KEY_F1 + 1))
+    }
+    match code {
+        KEY_F11 => Some("<FK11>".to_string()),
+        KEY_F12 => Some("<FK12>".to_string()),
+        _ => None
+    }
+}
+
+/// keysymを割り当てたキーを修飾キーとして使うための名前
+fn modifier_map(keysym: &str) -> Option<&'static str> {
+    match keysym {
+        "Control_L" | "Control_R" => Some("Control"),
+        "Shift_L" | "Shift_R" => Some("Shift"),
+        "Caps_Lock" => Some("Lock"),
+        "Alt_L" => Some("Mod1"),
+        "Num_Lock" => Some("Mod2"),
+        "Super_L" | "Super_R" => Some("Mod4"),
+        "ISO_Level3_Shift" => Some("Mod5"),
+        _ => None
+    }
+}
+
+/// キーの置き換えをXKBのsymbolsファイルにする
+pub fn export(remaps: &[Remap]) -> Result<String, Error> {
+    let keycode = Keycode::new();
+    let unknown = |code: u16| Error::new(Msg::ExportUnknownKey(
+        keycode.from_keycode(code).unwrap_or_else(|| code.to_string()), Target::Xkb.name()));
+
+    let mut keys = String::new();
+    let mut modifiers = String::new();
+    for r in remaps {
+        let name = key_name(r.from).ok_or_else(|| unknown(r.from))?;
+        let syms = match r.single(Target::Xkb)? {
+            Some(to) => code_to_keysyms(to).ok_or_else(|| unknown(to))?,
+            None => vec!["NoSymbol".to_string()]
+        };
+
+        keys += &format!("    replace key {} {{ [ {} ] }};\n", name, syms.join(", "));
+        if let Some(m) = modifier_map(&syms[0]) {
+            modifiers += &format!("    modifier_map {} {{ {} }};\n", m, name);
+        }
+    }
+
+    Ok(format!("\
+// generated by keymap export
+// install as ~/.config/xkb/symbols/keymap and add \"keymap\" to xkb_symbols
+partial modifier_keys alphanumeric_keys
+xkb_symbols \"keymap\" {{
+{}{}}};
+", keys, modifiers))
+}
+
+
+#[cfg(test)]
+mod test {
+    use interop::export;
+    use interop::Target;
+    use key_converter::rules::RulesParser;
+
+    #[test]
+    fn test_export() {
+        let rules = RulesParser::parse("CAPSLOCK -> 'LEFTCTRL\nQ -> 'A\n1 -> 'F1".as_bytes()).unwrap();
+        assert_eq!(export(Target::Xkb, &rules[""]).unwrap(), "\
+// generated by keymap export
+// install as ~/.config/xkb/symbols/keymap and add \"keymap\" to xkb_symbols
+partial modifier_keys alphanumeric_keys
+xkb_symbols \"keymap\" {
+    replace key <AE01> { [ F1 ] };
+    replace key <AD01> { [ a, A ] };
+    replace key <CAPS> { [ Control_L ] };
+    modifier_map Control { <CAPS> };
+};
+");
+    }
+}
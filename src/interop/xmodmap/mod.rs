@@ -0,0 +1,129 @@
+//! xmodmapの設定ファイル(.Xmodmap)を読み込む。
+//!
+//! ```text
+//! keycode 66 = Control_L        ! CapsLockをCtrlにする
+//! keysym Escape = grave asciitilde
+//! clear Lock
+//! ```
+//!
+//! keysymはUSキーボードで入力するキーに変換する。
+//! 修飾キーの割り当て(clear, add, remove)は、キーそのものを変換するので必要ない。
+
+use key_converter::rules::key_rule::Key;
+use message::Error;
+use message::Msg;
+use super::Imported;
+use super::SetsBuilder;
+use super::keysym_to_code;
+use super::keysym_to_keys;
+use super::remap;
+
+
+// Xのキーコードとevdevのキーコードの差
+const X_KEYCODE_OFFSET: u16 = 8;
+
+pub fn import(s: &str) -> Result<Imported, Error> {
+    let mut builder = SetsBuilder::new();
+
+    for (i, l) in s.lines().enumerate() {
+        let line = i + 1;
+        let l = l.split('!').next().unwrap().trim();
+        if l.is_empty() {
+            continue
+        }
+
+        let mut words = l.split_whitespace();
+        let command = words.next().unwrap();
+        let (lhs, rhs) = match l.find('=') {
+            Some(p) => (l[..p].trim(), l[p+1..].trim()),
+            None => (l, "")
+        };
+
+        // 変換元のキーコード
+        let from = match command {
+            "keycode" => match lhs["keycode".len()..].trim().parse::<u16>() {
+                Ok(n) if n >= X_KEYCODE_OFFSET => Some(n - X_KEYCODE_OFFSET),
+                _ => None
+            },
+            "keysym" => match keysym_to_code(lhs["keysym".len()..].trim()) {
+                Some((code, false)) => Some(code),
+                _ => None
+            },
+            "clear" | "add" | "remove" => {
+                builder.warn(line, Msg::ImportIgnored(l.to_string()));
+                continue
+            },
+            _ => {
+                builder.warn(line, Msg::ImportUnsupported(l.to_string()));
+                continue
+            }
+        };
+        let from = match from {
+            Some(f) => f,
+            None => {
+                builder.warn(line, Msg::ImportUnknownKey(lhs.to_string()));
+                continue
+            }
+        };
+
+        // 最初のkeysymを使う。二番目は最初のkeysymをShiftで入力したものでなければ
+        // 変換できない
+        let syms: Vec<&str> = rhs.split_whitespace().collect();
+        let to = match syms.first().map(|s| keysym_to_keys(s)) {
+            Some(Some(to)) => to,
+            // NoSymbolのキーは何も入力しない
+            Some(None) if syms == ["NoSymbol"] => Vec::new(),
+            _ => {
+                builder.warn(line, Msg::ImportUnknownKey(rhs.to_string()));
+                continue
+            }
+        };
+
+        let shifted = match &to[..] {
+            [Key::Con(code)] => syms.get(1).map(|s| keysym_to_code(s) == Some((*code, true))),
+            _ => None
+        };
+        if syms.len() > 2 || shifted == Some(false) || (syms.len() == 2 && shifted.is_none()) {
+            builder.warn(line, Msg::ImportUnsupported(rhs.to_string()));
+        }
+
+        if let Some(rule) = remap(from, to) {
+            builder.push("", line, rule);
+        }
+    }
+
+    Ok(builder.build())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::import;
+    use key_converter::rules::syntax::SyntaxTree;
+
+    #[test]
+    fn test_import() {
+        let imported = import(r#"
+! swap caps and control
+remove Lock = Caps_Lock
+keycode 66 = Control_L
+keycode 37 = Caps_Lock
+keysym Escape = grave asciitilde
+keycode 38 = a A
+keycode 10 = exclam 1
+keycode 24 = q Q ae
+pointer = 3 2 1
+keycode 133 = NoSymbol
+        "#).unwrap();
+
+        assert_eq!(SyntaxTree::from_sets(&imported.sets).format(), "\
+CAPSLOCK -> 'LEFTCTRL
+LEFTCTRL -> 'CAPSLOCK
+ESC      -> 'GRAVE
+1        -> 'LEFTSHIFT + '1
+LEFTMETA -> _
+");
+        let lines: Vec<usize> = imported.warnings.iter().map(|w| w.line).collect();
+        assert_eq!(lines, vec![3, 8, 9, 10]);
+    }
+}
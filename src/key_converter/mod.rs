@@ -1,97 +1,326 @@
 #![allow(dead_code)]
 /// キーを変換するためのモジュール
 
-use std::collections::HashSet;
 use std::collections::HashMap;
 use std::io::Read;
+use std::time::Duration;
+use std::time::Instant;
 
-mod rules;
+pub mod rules;
 use self::rules::Rules;
 use self::rules::RulesParser;
 use self::rules::key_rule::Key;
+use self::rules::key_rule::keycode::is_modifier;
+use self::rules::options::Repeat;
+use message::Error;
 
 
+/// 実際に押されているキー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pressed {
+    pub key: Key,
+    pub time: Instant, // 押された時刻
+    outputs: Vec<u16>, // 押したときに押したキー。リピートするキーを決めるために使う
+    repeat: Repeat,    // 押したときのルールで決まるリピートの方法
+    consumed: bool,    // ルールの切り替えに使ったキー。離すまでルールに適用しない
+    carried: Vec<Key>, // 切り替えたルールの右側のルール名以外のキー。
+                       // consumedのときはkeyの代わりに新しいルールに適用する
+}
+
+/// latchするルールがマッチしたときの状態
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Latch {
+    rule: usize,        // 選択されているルールの中の番号
+    triggers: Vec<Key>, // マッチさせた修飾キー以外の実際のキー。すべて離すと終わる
+    outputs: Vec<Key>,  // 押し続ける変換後のキー
+}
+
+/// 仮想キーボードへの出力。leave, push, repeatの順に出力する
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Output {
+    pub leave: Vec<u16>,
+    pub push: Vec<u16>,
+    pub repeat: Vec<u16>,
+}
+
+impl Output {
+    pub fn is_empty(&self) -> bool {
+        self.leave.is_empty() && self.push.is_empty() && self.repeat.is_empty()
+    }
+}
+
+// ソフトウェアでリピートするときの既定値
+pub const DEFAULT_REPEAT_DELAY: u64 = 500; // ミリ秒
+pub const DEFAULT_REPEAT_RATE: u64 = 30;   // 1秒あたりの回数
+// 使えるrateの上限。これより速いとリピートの間隔が1ミリ秒より短くなる
+pub const MAX_REPEAT_RATE: u64 = 1000;
+
+/// ソフトウェアでリピートするときの間隔
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepeatTiming {
+    pub delay: Duration,    // 押してからリピートが始まるまで
+    pub interval: Duration, // リピートの間隔
+}
+
+impl RepeatTiming {
+    /// delayはミリ秒、rateは1秒あたりの回数。
+    /// rateはis_valid_rateで確かめておく。間隔が0になることはない
+    pub fn new(delay: u64, rate: u64) -> RepeatTiming {
+        RepeatTiming {
+            delay: Duration::from_millis(delay),
+            interval: Duration::from_micros(1_000_000 / rate.max(1)).max(Duration::from_micros(1)),
+        }
+    }
+
+    /// rateが1からMAX_REPEAT_RATEまでならtrue
+    pub fn is_valid_rate(rate: u64) -> bool {
+        (1..=MAX_REPEAT_RATE).contains(&rate)
+    }
+}
+
 pub struct KeyConverter {
-    keys: HashSet<Key>, // 実際に押されているキーのリスト
+    keys: Vec<Pressed>, // 実際に押されているキーのリスト。押された順に並ぶ
     vkeys: Vec<Key>, // 仮想的に押されているキーのリスト
     rules_list: HashMap<String, Box<Rules>>,
     rules_name: Option<String>, // 現在選択されているRulesの名前
     rules: Option<Box<Rules>>,
+    timing: Option<RepeatTiming>, // Noneならキーボードのリピートを使う
+    next_repeat: Option<(u16, Instant)>, // ソフトウェアでリピートするキーと次の時刻
+    last_input: Instant, // 最後にキーを押すか離した時刻。timeoutに使う
+    latch: bool, // ルールにlatchの指定がないときにlatchするかどうか
+    latches: Vec<Latch>,
 }
 
 impl KeyConverter {
-    pub fn new<R: Read>(r: R) -> Result<KeyConverter, String> {
+    pub fn new<R: Read>(r: R) -> Result<KeyConverter, Error> {
         let rules_list = RulesParser::parse(r)?;
 
+        Ok(KeyConverter::from_rules_list(rules_list))
+    }
+
+    /// RulesParser::parseなどで作成したルールのリストから作成する
+    pub fn from_rules_list(rules_list: HashMap<String, Rules>) -> KeyConverter {
         let mut rules_list: HashMap<String, Box<Rules>> = 
              rules_list.into_iter().map(|(s, r)| (s, Box::new(r))).collect();
         let (name, rules) = rules_list.remove_entry("").unwrap();
 
-        Ok(KeyConverter {
-            keys: HashSet::new(),
+        KeyConverter {
+            keys: Vec::new(),
             vkeys: Vec::new(),
             rules_list,
             rules_name: Some(name),
-            rules: Some(rules)
-        })
+            rules: Some(rules),
+            timing: None,
+            next_repeat: None,
+            last_input: Instant::now(),
+            latch: false,
+            latches: Vec::new(),
+        }
+    }
+
+    /// ルールを入れ替える。押されている変換後のキーはすべて離し、
+    /// 押されている実際のキーは離すまで何も入力しない。
+    /// 返り値は離すキーのリスト
+    pub fn replace_rules(&mut self, rules_list: HashMap<String, Rules>) -> Vec<u16> {
+        let new = KeyConverter::from_rules_list(rules_list);
+        self.rules_list = new.rules_list;
+        self.rules_name = new.rules_name;
+        self.rules = new.rules;
+        self.release_all()
+    }
+
+    /// 押されているすべてのキーをなかったことにして、離すキーを返す
+    pub fn release_all(&mut self) -> Vec<u16> {
+        let mut leave: Vec<u16> = self.vkeys.iter().filter_map(|k| k.to_u16()).collect();
+        sort_leave(&mut leave);
+        self.vkeys.clear();
+        self.latches.clear();
+        self.next_repeat = None;
+
+        // 押されている実際のキーは、離したときに何も起こらないようにする
+        for p in &mut self.keys {
+            p.consumed = true;
+            p.carried.clear();
+        }
+
+        leave
     }
 
     pub fn get_rules_name(&self) -> &str {
         self.rules_name.as_ref().unwrap()
     }
     
+    /// 押されているキーを押された順に返す
+    pub fn pressed(&self) -> &[Pressed] {
+        &self.keys
+    }
+
+    /// 仮想的に押されているキー。Key::RuleとKey::Varも含む
+    pub fn outputs(&self) -> &[Key] {
+        &self.vkeys
+    }
+
+    /// ルールに適用するキー。ルールの切り替えに使ったキーは、
+    /// そのルールの右側のルール名以外のキーにする
+    fn active_keys<'a>(&'a self) -> impl Iterator<Item = &'a Key> + 'a {
+        self.keys.iter().flat_map(|p| if p.consumed {
+            p.carried.iter()
+        } else {
+            ::std::slice::from_ref(&p.key).iter()
+        })
+    }
+
+    /// 押されているキーにルールを適用する。
+    /// latchしたルールがマッチしなくなっていれば、そのルールの左側のキーを除いて
+    /// 適用し、変換後のキーを押したままにする
+    fn filter(&self) -> Vec<Key> {
+        let rules = self.rules.as_ref().unwrap();
+        let matched = rules.filter_steps(self.active_keys());
+        let broken: Vec<&Latch> = self.latches.iter()
+            .filter(|l| !matched.rules.contains(&l.rule))
+            .collect();
+        if broken.is_empty() {
+            return matched.keys
+        }
+
+        let mut vk = rules.filter(self.active_keys()
+            .filter(|k| !broken.iter().any(|l| l.triggers.contains(k))));
+        for o in broken.iter().flat_map(|l| l.outputs.iter()) {
+            if !vk.contains(o) {
+                vk.push(o.clone());
+            }
+        }
+        vk
+    }
+
+    /// ルールにlatchの指定がないときにlatchするかどうかを設定する
+    pub fn set_latch(&mut self, latch: bool) {
+        self.latch = latch;
+    }
+
+    /// 新しくマッチしたlatchするルールを記録する
+    fn add_latches(&mut self, applied: &[usize], vk: &[Key]) {
+        let rules = self.rules.as_ref().unwrap();
+        for i in applied {
+            let rule = &rules.get_list()[*i];
+            if !rule.options.latch.unwrap_or(self.latch)
+                || self.latches.iter().any(|l| l.rule == *i) {
+                continue
+            }
+
+            // 離すとルールがマッチしなくなる修飾キー以外のキーを探す
+            let triggers: Vec<Key> = self.active_keys()
+                .filter(|k| matches!(k, Key::Raw(c) if !is_modifier(*c)))
+                .filter(|k| !rules.filter_steps(self.active_keys().filter(|a| a != k))
+                    .rules.contains(i))
+                .cloned()
+                .collect();
+            if triggers.is_empty() {
+                continue
+            }
+
+            let outputs = rule.v.iter().filter(|v| vk.contains(v)).cloned().collect();
+            self.latches.push(Latch { rule: *i, triggers, outputs });
+        }
+    }
+
+    /// ソフトウェアでリピートするときの間隔を設定する。
+    /// Noneならキーボードが送るリピートを使う
+    pub fn set_repeat_timing(&mut self, timing: Option<RepeatTiming>) {
+        self.timing = timing;
+    }
+
+    /// 現在のルールでのリピートの間隔。
+    /// ルール名にrepeat_delayかrepeat_rateがあればソフトウェアでリピートする
+    fn repeat_timing(&self) -> Option<RepeatTiming> {
+        let options = self.rules.as_ref().unwrap().get_options();
+        if options.repeat_delay.is_none() && options.repeat_rate.is_none() {
+            return self.timing
+        }
+
+        let default = self.timing.unwrap_or(
+            RepeatTiming::new(DEFAULT_REPEAT_DELAY, DEFAULT_REPEAT_RATE));
+        Some(RepeatTiming {
+            delay: options.repeat_delay.map(Duration::from_millis).unwrap_or(default.delay),
+            interval: options.repeat_rate.map(|r| RepeatTiming::new(0, r).interval)
+                .unwrap_or(default.interval),
+        })
+    }
+
+    /// キーkのリピートの方法を決める。
+    /// kを左側に含む最後に適用したルールのオプション、ルール名のオプションの順に使う
+    fn repeat_mode(&self, k: u16, applied: &[usize]) -> Repeat {
+        let rules = self.rules.as_ref().unwrap();
+        applied.iter().rev()
+            .map(|i| &rules.get_list()[*i])
+            .filter(|r| r.k.contains(&Key::Raw(k)))
+            .find_map(|r| r.options.repeat)
+            .or(rules.get_options().repeat)
+            .unwrap_or(Repeat::On)
+    }
+
     /// 前回とのvkeysの差分を元に返り値を返す。
     /// 返り値は押すキーと離すキー
     pub fn push(&mut self, k: u16) -> (Vec<u16>, Vec<u16>) {
-        self.keys.insert(Key::Raw(k));
-        let vk = self.rules.as_ref().unwrap().filter(&self.keys);
-
-        // ルールを変える場合は何も押さずに全て離す
-        for v in &vk {
-            let name = if let Key::Rule(name) = v {
-                // 現在のルールと同じだったらcontinueする
-                if self.rules_name.as_ref().unwrap() == name {
-                    continue;
-                }
-                name
-            } else {
-                continue;
-            };
-
-            //// 新しいルールをself.rulesに入れる
-            let new = self.rules_list.remove(name).unwrap();
-            let old = self.rules.replace(new).unwrap();
-            let old_name = self.rules_name.replace(name.to_string()).unwrap();
-
-            //// 以前のルールをself.rules_listに入れる
-            self.rules_list.insert(old_name, old);
-        
-            // 何も押さずに、すべてのキーを離す
-            let vkeys = self.vkeys.iter()
-                .map(|v| v.to_u16().unwrap()).collect();
-            self.vkeys.clear();
+        self.push_at(k, Instant::now())
+    }
 
-            // 実際に押されているキーもすべてなかったことにする
-            self.keys.clear();
+    /// 押された時刻を指定してpushする
+    pub fn push_at(&mut self, k: u16, time: Instant) -> (Vec<u16>, Vec<u16>) {
+        let key = Key::Raw(k);
+        self.keys.retain(|p| p.key != key);
+        self.last_input = time;
+        let matched = self.rules.as_ref().unwrap()
+            .filter_steps(self.active_keys().chain(Some(&key)));
+        let repeat = self.repeat_mode(k, &matched.rules);
+        self.keys.push(Pressed {
+            key, time, outputs: Vec::new(), repeat, consumed: false, carried: Vec::new()
+        });
+        let mut vk = matched.keys;
 
-            return (Vec::new(), vkeys)
-        }
+        // ルールを変える場合は、押しているキーを新しいルールに適用し直す
+        let current = self.rules_name.clone();
+        let switch = vk.iter().find_map(|v| match v {
+            Key::Rule(name) if Some(name) != current.as_ref() => Some(name.clone()),
+            _ => None
+        });
+        let switched = match &switch {
+            Some(name) => self.switch_rules(name),
+            None => false
+        };
 
-        // Key::Ruleは除外する
-        // vk - vkeys の結果のキーを押す
-        let push = vk.iter()
-             .filter(|k| !self.vkeys.contains(&k))
-             .filter_map(|k| k.to_u16()).collect();
+        if switched {
+            // 切り替えたルールの右側のほかのキーは、切り替えに使ったキーを
+            // 離すまで押し続ける ('MUHENKAN + @ROMAJI)
+            let name = Key::Rule(switch.unwrap());
+            let rules = self.rules_list.get(current.as_ref().unwrap()).unwrap();
+            let carried = matched.rules.iter().rev()
+                .map(|i| &rules.get_list()[*i])
+                .find(|r| r.v.contains(&name))
+                .map(|r| r.v.iter().filter(|v| !matches!(v, Key::Rule(_))).cloned().collect())
+                .unwrap_or_default();
 
-        // vkeysに入っていて、vkに入っていないキーを離す
-        // Key::Ruleは除外する
-        // vkeys - vk の結果のキーを離す
-        let leave = self.vkeys.iter()
-             .filter(|k| !vk.contains(&k))
-             .filter_map(|k| k.to_u16()).collect();
+            // それ以外の押されているキーはそのまま新しいルールに適用する
+            let last = self.keys.last_mut().unwrap();
+            last.consumed = true;
+            last.carried = carried;
+            self.latches.clear();
+            vk = self.filter();
+        } else {
+            self.add_latches(&matched.rules, &vk);
+            vk = self.filter();
+        }
 
-        // self.vkeysの値を更新する
-        self.vkeys = vk;
+        let (push, leave) = self.update_vkeys(vk);
+        if !switched {
+            self.keys.last_mut().unwrap().outputs = push.clone();
+        }
+
+        // ソフトウェアでリピートするなら、最後に押したキーをリピートする
+        self.next_repeat = match self.repeat_timing() {
+            Some(t) if repeat != Repeat::Off && !switched => Some((k, time + t.delay)),
+            _ => None
+        };
 
         (push, leave)
     }
@@ -100,24 +329,504 @@ impl KeyConverter {
     /// ここではState::Pushとなるキーは返さない。
     /// 返り値は離すキーのリスト
     pub fn leave(&mut self, k: u16) -> Vec<u16> { 
-        self.keys.remove(&Key::Raw(k));
-        let vk = self.rules.as_ref().unwrap().filter(&self.keys);
+        self.leave_at(k, Instant::now())
+    }
+
+    /// 離された時刻を指定してleaveする
+    pub fn leave_at(&mut self, k: u16, time: Instant) -> Vec<u16> { 
+        self.keys.retain(|p| p.key != Key::Raw(k));
+        self.last_input = time;
+        let keys = &self.keys;
+        self.latches.retain(|l| l.triggers.iter().any(|t| keys.iter().any(|p| p.key == *t)));
+        if self.next_repeat.is_some_and(|(r, _)| r == k) {
+            self.next_repeat = None;
+        }
+        let vk = self.filter();
         
+        // 離したことで押されるキーはない
+        let (_, leave) = self.update_vkeys(vk);
+        leave
+    }
+
+    /// 選択されているルールをnameに変える。nameのルールがなければ何もしない
+    fn switch_rules(&mut self, name: &str) -> bool {
+        //// 新しいルールをself.rulesに入れる
+        let new = match self.rules_list.remove(name) {
+            Some(r) => r,
+            None => return false
+        };
+        let old = self.rules.replace(new).unwrap();
+        let old_name = self.rules_name.replace(name.to_string()).unwrap();
+
+        //// 以前のルールをself.rules_listに入れる
+        self.rules_list.insert(old_name, old);
+
+        true
+    }
+
+    /// self.vkeysをvkにして、押すキーと離すキーを返す。
+    /// Key::RuleとKey::Varは入力しないので除外する
+    fn update_vkeys(&mut self, vk: Vec<Key>) -> (Vec<u16>, Vec<u16>) {
+        // vk - vkeys の結果のキーを押す
+        let mut push: Vec<u16> = vk.iter()
+             .filter(|k| !self.vkeys.contains(k))
+             .filter_map(|k| k.to_u16()).collect();
+        sort_push(&mut push);
+
         // vkeysに入っていて、vkに入っていないキーを離す
-        // Key::Ruleは除外する
         // vkeys - vk の結果のキーを離す
-        let leave = self.vkeys.iter()
-             .filter(|k| !vk.contains(&k))
+        let mut leave: Vec<u16> = self.vkeys.iter()
+             .filter(|k| !vk.contains(k))
              .filter_map(|k| k.to_u16()).collect();
+        sort_leave(&mut leave);
 
         // self.vkeysの値を更新する
         self.vkeys = vk;
 
-        leave
+        (push, leave)
+    }
+
+    /// キーボードから送られたキーkのリピートに対する出力を返す。
+    /// ソフトウェアでリピートしているときは何も出力しない
+    pub fn repeat(&self, k: u16) -> Output {
+        if self.repeat_timing().is_some() {
+            return Output::default()
+        }
+        self.repeat_output(k)
+    }
+
+    /// 何も押されていないまま、選択されているルールのtimeoutが過ぎる時刻
+    fn timeout_deadline(&self) -> Option<Instant> {
+        if !self.keys.is_empty() {
+            return None
+        }
+        let timeout = self.rules.as_ref().unwrap().get_options().timeout?;
+        Some(self.last_input + Duration::from_millis(timeout))
+    }
+
+    /// tickを呼ぶ次の時刻。ソフトウェアでのリピートとルールのtimeoutのうち早いほう
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let repeat = self.next_repeat.map(|(_, t)| t);
+        match (repeat, self.timeout_deadline()) {
+            (Some(r), Some(t)) => Some(r.min(t)),
+            (r, t) => r.or(t)
+        }
+    }
+
+    /// 時刻nowまでにソフトウェアでリピートするキーと、
+    /// timeoutでルールを戻したときに変わるキーを出力する
+    pub fn tick(&mut self, now: Instant) -> Output {
+        if let Some((k, next)) = self.next_repeat {
+            if next <= now {
+                // 処理が遅れても、リピートをまとめて出力はしない
+                let interval = self.repeat_timing().map(|t| t.interval).unwrap_or_default();
+                self.next_repeat = Some((k, (next + interval).max(now)));
+                return self.repeat_output(k)
+            }
+        }
+
+        if self.timeout_deadline().is_some_and(|t| t <= now) {
+            self.last_input = now;
+            let name = self.rules.as_ref().unwrap().get_options()
+                .timeout_rule.clone().unwrap_or_default();
+            return self.select_rules(&name).unwrap_or_default()
+        }
+
+        Output::default()
+    }
+
+    /// 押されているキーはそのままでルールを切り替えて、変わるキーを出力する。
+    /// ルールがなければNone
+    pub fn select_rules(&mut self, name: &str) -> Option<Output> {
+        if !self.switch_rules(name) {
+            return None
+        }
+        self.latches.clear();
+        let vk = self.filter();
+        let (push, leave) = self.update_vkeys(vk);
+        Some(Output { leave, push, repeat: Vec::new() })
+    }
+
+    /// 押し続けられているキーkのリピートで出力するキー。
+    /// kを押したときに押したキーのうち、まだ押されている修飾キー以外のキーを使う
+    fn repeat_output(&self, k: u16) -> Output {
+        let pressed = match self.keys.iter().find(|p| p.key == Key::Raw(k)) {
+            Some(p) => p,
+            None => return Output::default()
+        };
+        let norepeat = &self.rules.as_ref().unwrap().get_options().norepeat;
+        let held: Vec<u16> = pressed.outputs.iter()
+            .filter(|o| !is_modifier(**o))
+            .filter(|o| !norepeat.iter().any(|n| n.to_u16() == Some(**o)))
+            .filter(|o| self.vkeys.iter().any(|v| v.to_u16() == Some(**o)))
+            .cloned()
+            .collect();
+
+        match pressed.repeat {
+            Repeat::On => Output {
+                repeat: held.last().cloned().into_iter().collect(),
+                ..Output::default()
+            },
+            // 修飾キーはそのままにして、それ以外を押し直す
+            Repeat::Chord => Output { leave: held.clone(), push: held, repeat: Vec::new() },
+            Repeat::Off => Output::default()
+        }
     }
 
     pub fn filter_to_string(&mut self) -> String {
-        self.rules.as_ref().unwrap().filter_to_string(&self.keys)
+        self.rules.as_ref().unwrap().filter_to_string(self.active_keys())
+    }
+
+    /// 押されているキーにマッチしたルール
+    pub fn matched_rules(&self) -> Vec<String> {
+        self.rules.as_ref().unwrap().matched_rules(self.active_keys())
     }
 }
 
+/// 押すキーは修飾キーを先にする。
+/// アプリケーションが修飾キーなしのキーとして受け取らないようにするため。
+/// それ以外はルールの順番のままにする
+fn sort_push(keys: &mut [u16]) {
+    keys.sort_by_key(|k| !is_modifier(*k));
+}
+
+/// 離すキーは修飾キーを後にする
+fn sort_leave(keys: &mut [u16]) {
+    keys.sort_by_key(|k| is_modifier(*k));
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use std::time::Instant;
+    use super::KeyConverter;
+    use super::Output;
+    use super::RepeatTiming;
+    use super::MAX_REPEAT_RATE;
+    use super::rules::key_rule::keycode::*;
+
+    #[test]
+    fn test_modifier_order() {
+        let mut kc = KeyConverter::new(r#"
+        A -> 'T + 'RIGHTSHIFT + 'LEFTCTRL
+        B -> 'X + 'Y
+        "#.as_bytes()).unwrap();
+
+        // 何度実行しても同じ順番になる
+        for _ in 0..20 {
+            assert_eq!(kc.push(KEY_A), (vec![KEY_RIGHTSHIFT, KEY_LEFTCTRL, KEY_T], vec![]));
+            assert_eq!(kc.leave(KEY_A), vec![KEY_T, KEY_RIGHTSHIFT, KEY_LEFTCTRL]);
+
+            assert_eq!(kc.push(KEY_B), (vec![KEY_X, KEY_Y], vec![]));
+            assert_eq!(kc.leave(KEY_B), vec![KEY_X, KEY_Y]);
+        }
+    }
+
+    #[test]
+    fn test_modifier_order_on_rule_switch() {
+        let mut kc = KeyConverter::new(r#"
+        A -> 'LEFTSHIFT + 'T
+        B -> @RULE
+        @RULE
+        "#.as_bytes()).unwrap();
+
+        // 押し続けているAは新しいルールで変換し直す
+        kc.push(KEY_A);
+        assert_eq!(kc.push(KEY_B), (vec![KEY_A], vec![KEY_T, KEY_LEFTSHIFT]));
+    }
+
+    #[test]
+    fn test_keep_keys_on_rule_switch() {
+        let mut kc = KeyConverter::new(r#"
+        CAPSLOCK -> 'LEFTCTRL
+        HENKAN -> @SYMBOL
+        @SYMBOL
+        CAPSLOCK -> 'LEFTCTRL
+        J -> 'LEFTBRACE
+        HENKAN -> @
+        "#.as_bytes()).unwrap();
+
+        // Ctrlを押したまま切り替えても、Ctrlは押されたまま
+        assert_eq!(kc.push(KEY_CAPSLOCK), (vec![KEY_LEFTCTRL], vec![]));
+        assert_eq!(kc.push(KEY_HENKAN), (vec![], vec![]));
+        assert_eq!(kc.get_rules_name(), "SYMBOL");
+        assert_eq!(kc.push(KEY_J), (vec![KEY_LEFTBRACE], vec![]));
+
+        // 切り替えに使ったキーは離しても何も出力しない
+        assert!(kc.leave(KEY_HENKAN).is_empty());
+        assert_eq!(kc.leave(KEY_J), vec![KEY_LEFTBRACE]);
+        assert_eq!(kc.leave(KEY_CAPSLOCK), vec![KEY_LEFTCTRL]);
+        assert!(kc.pressed().is_empty());
+    }
+
+    #[test]
+    fn test_press_order() {
+        let mut kc = KeyConverter::new("A > B -> 'C".as_bytes()).unwrap();
+
+        assert_eq!(kc.push(KEY_A), (vec![KEY_A], vec![]));
+        assert_eq!(kc.push(KEY_B), (vec![KEY_C], vec![KEY_A]));
+        assert_eq!(kc.pressed().iter().map(|p| p.key.to_u16().unwrap()).collect::<Vec<_>>(),
+                   vec![KEY_A, KEY_B]);
+        kc.leave(KEY_A);
+        kc.leave(KEY_B);
+
+        // 順番が違えばマッチしない
+        assert_eq!(kc.push(KEY_B), (vec![KEY_B], vec![]));
+        assert_eq!(kc.push(KEY_A), (vec![KEY_A], vec![]));
+    }
+
+    fn repeated(kc: &KeyConverter, k: u16) -> Vec<u16> {
+        kc.repeat(k).repeat
+    }
+
+    #[test]
+    fn test_repeat() {
+        let mut kc = KeyConverter::new(r#"
+        A -> 'LEFTSHIFT + 'X
+        LEFTCTRL + B -> 'BACKSPACE
+        CAPSLOCK -> 'LEFTCTRL
+        "#.as_bytes()).unwrap();
+
+        kc.push(KEY_A);
+        assert_eq!(repeated(&kc, KEY_A), vec![KEY_X]);
+        kc.push(KEY_C);
+        assert_eq!(repeated(&kc, KEY_C), vec![KEY_C]);
+        assert_eq!(repeated(&kc, KEY_A), vec![KEY_X]);
+        kc.leave(KEY_A);
+        assert_eq!(repeated(&kc, KEY_A), Vec::<u16>::new());
+
+        // 組み合わせで変換されたキーをリピートする
+        kc.push(KEY_LEFTCTRL);
+        kc.push(KEY_B);
+        assert_eq!(repeated(&kc, KEY_B), vec![KEY_BACKSPACE]);
+
+        // 修飾キーはリピートしない
+        kc.push(KEY_CAPSLOCK);
+        assert_eq!(repeated(&kc, KEY_CAPSLOCK), Vec::<u16>::new());
+    }
+
+    #[test]
+    fn test_repeat_options() {
+        let mut kc = KeyConverter::new(r#"
+        A -> 'X + 'Y [repeat=chord]
+        B -> 'B [repeat=off]
+        C -> 'ENTER
+        D -> @NOREPEAT
+        @NOREPEAT [repeat=off]
+        E -> 'E [repeat=on]
+        "#.as_bytes()).unwrap();
+
+        kc.push(KEY_A);
+        assert_eq!(kc.repeat(KEY_A), Output {
+            leave: vec![KEY_X, KEY_Y], push: vec![KEY_X, KEY_Y], repeat: vec![] });
+        kc.push(KEY_B);
+        assert!(kc.repeat(KEY_B).is_empty());
+        kc.push(KEY_C);
+        assert_eq!(repeated(&kc, KEY_C), vec![KEY_ENTER]);
+        for k in [KEY_A, KEY_B, KEY_C] {
+            kc.leave(k);
+        }
+
+        // ルールを切り替えると、切り替えたルールのオプションを使う
+        kc.push(KEY_D);
+        kc.leave(KEY_D);
+        kc.push(KEY_F);
+        assert!(kc.repeat(KEY_F).is_empty());
+        kc.push(KEY_E);
+        assert_eq!(repeated(&kc, KEY_E), vec![KEY_E]);
+    }
+
+    #[test]
+    fn test_software_repeat() {
+        let mut kc = KeyConverter::new(r#"
+        A -> 'X
+        @SLOW [repeat_delay=1000, norepeat='ENTER]
+        "#.as_bytes()).unwrap();
+        kc.set_repeat_timing(Some(RepeatTiming::new(500, 10)));
+        let t = Instant::now();
+        let ms = |n| t + Duration::from_millis(n);
+
+        kc.push_at(KEY_A, t);
+        assert_eq!(kc.next_deadline(), Some(ms(500)));
+        assert!(kc.tick(ms(499)).is_empty());
+        assert_eq!(kc.tick(ms(500)).repeat, vec![KEY_X]);
+        assert_eq!(kc.next_deadline(), Some(ms(600)));
+
+        // キーボードのリピートは無視する
+        assert!(kc.repeat(KEY_A).is_empty());
+
+        kc.leave(KEY_A);
+        assert_eq!(kc.next_deadline(), None);
+        assert!(kc.tick(ms(1000)).is_empty());
+    }
+
+    #[test]
+    fn test_repeat_timing() {
+        assert_eq!(RepeatTiming::new(500, 30).interval, Duration::from_micros(33_333));
+        assert_eq!(RepeatTiming::new(500, MAX_REPEAT_RATE).interval, Duration::from_millis(1));
+        assert!(RepeatTiming::is_valid_rate(1) && RepeatTiming::is_valid_rate(MAX_REPEAT_RATE));
+        assert!(!RepeatTiming::is_valid_rate(0) && !RepeatTiming::is_valid_rate(MAX_REPEAT_RATE + 1));
+    }
+
+    #[test]
+    fn test_composite_rule_switch() {
+        let mut kc = KeyConverter::new(r#"
+        F1 -> 'MUHENKAN + @ROMAJI
+        F2 -> 'LEFTSHIFT + @SYMBOL
+        @ROMAJI
+        F1 -> @
+        @SYMBOL
+        LEFTSHIFT + J -> 'X
+        'LEFTSHIFT + K -> 'Y
+        "#.as_bytes()).unwrap();
+
+        // キーを送ってからルールを切り替える
+        assert_eq!(kc.push(KEY_F1), (vec![KEY_MUHENKAN], vec![]));
+        assert_eq!(kc.get_rules_name(), "ROMAJI");
+        assert_eq!(kc.leave(KEY_F1), vec![KEY_MUHENKAN]);
+        kc.push(KEY_F1);
+        kc.leave(KEY_F1);
+
+        // F2を押している間はSHIFTを押し続け、新しいルールにも適用する
+        assert_eq!(kc.push(KEY_F2), (vec![KEY_LEFTSHIFT], vec![]));
+        assert_eq!(kc.push(KEY_J), (vec![KEY_J], vec![]));
+        assert_eq!(kc.leave(KEY_J), vec![KEY_J]);
+        assert_eq!(kc.push(KEY_K), (vec![KEY_Y], vec![KEY_LEFTSHIFT]));
+    }
+
+    #[test]
+    fn test_same_carried_keys() {
+        let mut kc = KeyConverter::new(r#"
+        F1 -> 'LEFTSHIFT + @SYMBOL
+        @SYMBOL
+        F2 -> 'LEFTSHIFT + @SHIFTED
+        @SHIFTED
+        'LEFTSHIFT + K -> 'Y
+        "#.as_bytes()).unwrap();
+
+        // 2つのキーが同じキーを引き継いでも、Kを押すまではマッチしない
+        assert_eq!(kc.push(KEY_F1), (vec![KEY_LEFTSHIFT], vec![]));
+        assert_eq!(kc.push(KEY_F2), (vec![], vec![]));
+        assert_eq!(kc.get_rules_name(), "SHIFTED");
+        assert_eq!(kc.push(KEY_K), (vec![KEY_Y], vec![KEY_LEFTSHIFT]));
+    }
+
+    #[test]
+    fn test_virtual_key() {
+        let mut kc = KeyConverter::new(r#"
+        CAPSLOCK -> $NAV
+        $NAV + J -> 'LEFT
+        $NAV + K -> $NAV + 'LEFTCTRL
+        "#.as_bytes()).unwrap();
+
+        // 仮想的なキーは入力しない
+        assert_eq!(kc.push(KEY_CAPSLOCK), (vec![], vec![]));
+        assert_eq!(kc.push(KEY_J), (vec![KEY_LEFT], vec![]));
+        assert_eq!(kc.leave(KEY_J), vec![KEY_LEFT]);
+        assert_eq!(kc.push(KEY_K), (vec![KEY_LEFTCTRL], vec![]));
+        assert_eq!(kc.leave(KEY_CAPSLOCK), vec![KEY_LEFTCTRL]);
+    }
+
+    #[test]
+    fn test_block() {
+        let mut kc = KeyConverter::new(r#"
+        INSERT -> _
+        F12 -> @GAME
+        @GAME [passthrough=block]
+        W -> 'UP
+        LEFTMETA -> _
+        F12 -> @
+        "#.as_bytes()).unwrap();
+
+        assert_eq!(kc.push(KEY_INSERT), (vec![], vec![]));
+        assert_eq!(kc.push(KEY_A), (vec![KEY_A], vec![]));
+        kc.leave(KEY_A);
+
+        // マッチしなかったキーは入力しない
+        kc.push(KEY_F12);
+        kc.leave(KEY_F12);
+        assert_eq!(kc.push(KEY_A), (vec![], vec![]));
+        assert_eq!(kc.push(KEY_W), (vec![KEY_UP], vec![]));
+        assert_eq!(kc.push(KEY_LEFTMETA), (vec![], vec![]));
+    }
+
+    #[test]
+    fn test_replace_rules() {
+        let mut kc = KeyConverter::new("CAPSLOCK -> 'LEFTCTRL\nA -> 'B".as_bytes()).unwrap();
+        kc.push(KEY_CAPSLOCK);
+        kc.push(KEY_A);
+
+        let rules = ::key_converter::rules::RulesParser::parse("A -> 'C".as_bytes()).unwrap();
+        assert_eq!(kc.replace_rules(rules), vec![KEY_B, KEY_LEFTCTRL]);
+
+        // 入れ替える前から押されていたキーは何も入力しない
+        assert!(kc.leave(KEY_A).is_empty());
+        assert_eq!(kc.push(KEY_A), (vec![KEY_C], vec![]));
+        assert!(kc.leave(KEY_CAPSLOCK).is_empty());
+    }
+
+    #[test]
+    fn test_latch() {
+        let source = r#"
+        LEFTCTRL + A -> 'BACKSPACE [latch]
+        LEFTCTRL + B -> 'DELETE
+        "#;
+        let mut kc = KeyConverter::new(source.as_bytes()).unwrap();
+
+        // Ctrlを先に離しても、Aを離すまでBACKSPACEを押し続ける
+        assert_eq!(kc.push(KEY_LEFTCTRL), (vec![KEY_LEFTCTRL], vec![]));
+        assert_eq!(kc.push(KEY_A), (vec![KEY_BACKSPACE], vec![KEY_LEFTCTRL]));
+        assert!(kc.leave(KEY_LEFTCTRL).is_empty());
+        assert_eq!(kc.leave(KEY_A), vec![KEY_BACKSPACE]);
+
+        // latchしないルールはCtrlを離すとDELETEも離す
+        kc.push(KEY_LEFTCTRL);
+        kc.push(KEY_B);
+        assert_eq!(kc.leave(KEY_LEFTCTRL), vec![KEY_DELETE]);
+        kc.leave(KEY_B);
+
+        // すべてのルールでlatchする
+        let mut kc = KeyConverter::new(source.as_bytes()).unwrap();
+        kc.set_latch(true);
+        kc.push(KEY_LEFTCTRL);
+        kc.push(KEY_B);
+        assert!(kc.leave(KEY_LEFTCTRL).is_empty());
+        assert_eq!(kc.push(KEY_C), (vec![KEY_C], vec![]));
+        assert_eq!(kc.leave(KEY_B), vec![KEY_DELETE]);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut kc = KeyConverter::new(r#"
+        CAPSLOCK -> 'LEFTCTRL
+        HENKAN -> @SYMBOL
+        @SYMBOL [timeout=3000]
+        J -> 'LEFTBRACE
+        K -> @NUMBER
+        @NUMBER [timeout=1000, timeout_rule=@SYMBOL]
+        "#.as_bytes()).unwrap();
+        let t = Instant::now();
+        let ms = |n| t + Duration::from_millis(n);
+
+        kc.push_at(KEY_HENKAN, ms(0));
+        kc.leave_at(KEY_HENKAN, ms(100));
+        assert_eq!(kc.next_deadline(), Some(ms(3100)));
+
+        // キーを押している間は戻らない
+        kc.push_at(KEY_J, ms(1000));
+        assert_eq!(kc.next_deadline(), None);
+        kc.leave_at(KEY_J, ms(2000));
+        assert!(kc.tick(ms(4000)).is_empty());
+        assert_eq!(kc.get_rules_name(), "SYMBOL");
+
+        // timeout_ruleのルールに戻る
+        kc.push_at(KEY_K, ms(5000));
+        kc.leave_at(KEY_K, ms(5000));
+        assert!(kc.tick(ms(6000)).is_empty());
+        assert_eq!(kc.get_rules_name(), "SYMBOL");
+        kc.tick(ms(9000));
+        assert_eq!(kc.get_rules_name(), "");
+        assert_eq!(kc.next_deadline(), None);
+    }
+}
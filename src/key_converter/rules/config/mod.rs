@@ -0,0 +1,254 @@
+//! TOMLとJSONの設定ファイルからルールを読み書きする。
+//! 設定ファイルはルールの配列を持ち、それぞれのルールはRulesと同じ形をしている。
+//!
+//! ```toml
+//! [[rules]]
+//! name = ""
+//! list = [
+//!     { k = ["CapsLock"], v = ["'LeftCtrl"] },
+//!     { k = ["Q"], v = ["@RULE_1"] },
+//! ]
+//!
+//! [[rules]]
+//! name = "RULE_1"
+//! extend = ""
+//! list = [
+//!     { k = ["F"], v = ["'B"], ove = true },
+//! ]
+//! ```
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+use serde_json;
+use toml;
+
+use super::Rules;
+use super::RulesParser;
+use super::ParsedRules;
+use super::syntax::SyntaxTree;
+use message::Error;
+use message::Msg;
+
+
+/// ルールを記述する形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text, // 独自のルールファイルの形式
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// ファイルの拡張子から形式を決める。
+    /// .tomlと.json以外はルールファイルとして扱う
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Format {
+        let ext = path.as_ref().extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+
+        match ext.as_deref() {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Text
+        }
+    }
+}
+
+/// 設定ファイルの構造体
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RulesConfig {
+    #[serde(default)]
+    pub rules: Vec<Rules>,
+}
+
+/// 指定した形式でルールを読み出し、RulesParser::parseと同じ結果を返す
+pub fn load<R: Read>(r: R, format: Format)
+    -> Result<HashMap<String, Rules>, Error>
+{
+    match format {
+        Format::Text => RulesParser::parse(r),
+        _ => {
+            let sets = read_sets(r, format)?;
+            if let Some(e) = validate(&sets).into_iter().next() {
+                return Err(e)
+            }
+            let parsed_rules_list = sets.into_iter()
+                .map(|rules| (rules.name.clone(), ParsedRules::from_rules(rules)))
+                .collect();
+            RulesParser::resolve(parsed_rules_list)
+        }
+    }
+}
+
+/// loadと同じようにルールを読み出すが、エラーがあっても最後まで読んで
+/// すべてのエラーを集める。返り値はエラーのなかったルールのリストとエラー
+pub fn check<R: Read>(r: R, format: Format) -> (HashMap<String, Rules>, Vec<Error>) {
+    match format {
+        Format::Text => RulesParser::parse_all(r),
+        _ => match read_sets(r, format) {
+            Ok(sets) => {
+                let mut errors = validate(&sets);
+                let (rules_list, e) = RulesParser::resolve_all(sets.into_iter()
+                    .map(|rules| (rules.name.clone(), ParsedRules::from_rules(rules)))
+                    .collect());
+                errors.extend(e);
+                (rules_list, errors)
+            },
+            Err(e) => (HashMap::new(), vec![e])
+        }
+    }
+}
+
+/// 設定ファイルのオプションを、ルールファイルのオプションと同じように確かめる
+fn validate(sets: &[Rules]) -> Vec<Error> {
+    sets.iter()
+        .filter_map(|r| r.get_options().validate().err().map(|e| e.with_rule(r.get_name())))
+        .collect()
+}
+
+/// 継承を解決せずにルールのリストを読み出す。
+/// ルールファイルの場合はファイルに書かれた順番に並ぶ
+pub fn read_sets<R: Read>(mut r: R, format: Format) -> Result<Vec<Rules>, Error> {
+    if format == Format::Text {
+        let mut sets: Vec<ParsedRules> =
+            RulesParser::parse_sets(r)?.into_values().collect();
+        sets.sort_by_key(|p| p.line);
+        return Ok(sets.into_iter().map(|p| p.into_rules()).collect())
+    }
+
+    let mut s = String::new();
+    if let Err(e) = r.read_to_string(&mut s) {
+        return Err(Msg::InvalidConfig(e.to_string()).into())
+    }
+
+    let config: RulesConfig = match format {
+        Format::Toml => toml::from_str(&s)
+            .map_err(|e| Error::new(Msg::InvalidConfig(e.to_string())))?,
+        _ => serde_json::from_str(&s)
+            .map_err(|e| Error::new(Msg::InvalidConfig(e.to_string())))?,
+    };
+
+    Ok(config.rules)
+}
+
+/// ルールのリストを指定した形式の文字列に変換する
+pub fn write_sets(sets: Vec<Rules>, format: Format) -> Result<String, Error> {
+    let config = RulesConfig { rules: sets };
+
+    match format {
+        Format::Toml => toml::to_string_pretty(&config)
+            .map_err(|e| Error::new(Msg::InvalidConfig(e.to_string()))),
+        Format::Json => serde_json::to_string_pretty(&config)
+            .map(|s| s + "\n")
+            .map_err(|e| Error::new(Msg::InvalidConfig(e.to_string()))),
+        Format::Text => Ok(SyntaxTree::from_sets(&config.rules).format())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::Format;
+    use super::check;
+    use super::load;
+    use super::read_sets;
+    use super::write_sets;
+    use super::super::RulesParser;
+
+    const TEXT: &str = r#"
+    CapsLock -> 'LeftCtrl
+    'LeftCtrl + 'A -> 'BackSpace
+    Q -> @RULE_1
+
+    @RULE_1 : @
+      F -> 'B
+      Q -!> @
+    "#;
+
+    const TOML: &str = r#"
+    [[rules]]
+    name = ""
+    list = [
+        { k = ["CapsLock"], v = ["'LeftCtrl"] },
+        { k = ["'LeftCtrl", "'A"], v = ["'BackSpace"] },
+        { k = ["Q"], v = ["@RULE_1"] },
+    ]
+
+    [[rules]]
+    name = "RULE_1"
+    extend = ""
+    list = [
+        { k = ["F"], v = ["'B"] },
+        { k = ["Q"], v = ["@"], ove = true },
+    ]
+    "#;
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(Format::from_path("keymap.toml"), Format::Toml);
+        assert_eq!(Format::from_path("a/keymap.JSON"), Format::Json);
+        assert_eq!(Format::from_path("keymap.txt"), Format::Text);
+        assert_eq!(Format::from_path("keymap"), Format::Text);
+    }
+
+    #[test]
+    fn test_load() {
+        let text = RulesParser::parse(TEXT.as_bytes()).unwrap();
+        assert_eq!(load(TOML.as_bytes(), Format::Toml).unwrap(), text);
+
+        // TOML -> JSON -> TOMLと変換しても同じルールになる
+        let sets = read_sets(TOML.as_bytes(), Format::Toml).unwrap();
+        let json = write_sets(sets, Format::Json).unwrap();
+        assert_eq!(load(json.as_bytes(), Format::Json).unwrap(), text);
+
+        let sets = read_sets(json.as_bytes(), Format::Json).unwrap();
+        let toml = write_sets(sets, Format::Toml).unwrap();
+        assert_eq!(load(toml.as_bytes(), Format::Toml).unwrap(), text);
+
+        // ルールファイルから変換する
+        let sets = read_sets(TEXT.as_bytes(), Format::Text).unwrap();
+        assert_eq!(sets[0].get_name(), "");
+        assert_eq!(sets[1].get_name(), "RULE_1");
+        let json = write_sets(sets, Format::Json).unwrap();
+        assert_eq!(load(json.as_bytes(), Format::Json).unwrap(), text);
+
+        // ルールファイルへ変換する
+        let sets = read_sets(TOML.as_bytes(), Format::Toml).unwrap();
+        let s = write_sets(sets, Format::Text).unwrap();
+        assert_eq!(load(s.as_bytes(), Format::Text).unwrap(), text);
+    }
+
+    #[test]
+    fn test_load_error() {
+        let e = load(r#"{ "rules": [ { "list": [ { "k": ["XYZ"], "v": ["'A"] } ] } ] }"#.as_bytes(),
+                     Format::Json).unwrap_err();
+        assert_eq!(e.code(), "E110");
+
+        let e = load(r#"{ "rules": [ { "list": [
+            { "k": ["A"], "v": ["'B"] },
+            { "k": ["A"], "v": ["'C"] }
+        ] } ] }"#.as_bytes(), Format::Json).unwrap_err();
+        assert_eq!(e.code(), "E107");
+
+        // オプションもルールファイルと同じように確かめる
+        let toml = r#"
+        [[rules]]
+        name = "X"
+        options = { repeat_rate = 0 }
+        "#;
+        let e = load(toml.as_bytes(), Format::Toml).unwrap_err();
+        assert_eq!((e.code(), e.rule.as_deref()), ("E113", Some("X")));
+        let (_, errors) = check(toml.as_bytes(), Format::Toml);
+        assert_eq!(errors.iter().map(|e| e.code()).collect::<Vec<_>>(), vec!["E113"]);
+        let toml = toml.replace("repeat_rate = 0", "repeat_rate = 1000");
+        assert!(load(toml.as_bytes(), Format::Toml).is_ok());
+
+        // ルールファイルのエラーには1から数えた行番号を付ける
+        let text = "A -> 'B\n\n@X [timeout_rule=@Y]\n  A -> 'B\n  A -> 'C\n";
+        let (_, errors) = check(text.as_bytes(), Format::Text);
+        let errors: Vec<(&str, Option<usize>)> = errors.iter().map(|e| (e.code(), e.line)).collect();
+        assert_eq!(errors, vec![("E114", Some(3)), ("E107", Some(5))]);
+    }
+}
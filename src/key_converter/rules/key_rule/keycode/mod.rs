@@ -277,8 +277,10 @@ macro_rules! KEY {
     };
 }
 
-fn get_keycode_map() -> HashMap<String, u16> {
-    let keycode_map: HashMap<String, u16> = [
+/// キーの名前とキーコードのリスト。
+/// 同じキーコードに複数の名前がある場合は、先にあるものを正式な名前とする
+fn get_keycode_list() -> Vec<(String, u16)> {
+    let keycode_list: Vec<(String, u16)> = [
 KEY!( "RESERVED" , KEY_RESERVED ),
 KEY!( "ESC" , KEY_ESC ),
 KEY!( "1" , KEY_1 ),
@@ -532,21 +534,39 @@ KEY!( "INTERNAL_2" , 251),
 KEY!( "INTERNAL_3" , 252),
 KEY!( "INTERNAL_4" , 253),
 KEY!( "INTERNAL_5" , 254),
-        ].iter().cloned().collect();
+        ].to_vec();
 
-    keycode_map
+    keycode_list
+}
+
+/// 修飾キーかどうか。
+/// 修飾キーは他のキーより先に押し、後に離す
+pub fn is_modifier(code: u16) -> bool {
+    matches!(code,
+        KEY_LEFTCTRL | KEY_RIGHTCTRL | KEY_LEFTSHIFT | KEY_RIGHTSHIFT |
+        KEY_LEFTALT | KEY_RIGHTALT | KEY_LEFTMETA | KEY_RIGHTMETA)
 }
 
 use std::collections::HashMap;
 
 pub struct Keycode {
     keycode_map: HashMap<String, u16>,
+    name_map: HashMap<u16, String>, // キーコードから正式な名前を引く
+    list: Vec<(String, u16)>,
 }
 
 impl Keycode {
     pub fn new() -> Keycode {
+        let list = get_keycode_list();
+        let mut name_map = HashMap::new();
+        for (name, code) in &list {
+            name_map.entry(*code).or_insert_with(|| name.clone());
+        }
+
         Keycode {
-            keycode_map: get_keycode_map(),
+            keycode_map: list.iter().cloned().collect(),
+            name_map,
+            list,
         }
     }
 
@@ -561,12 +581,23 @@ impl Keycode {
     /// キーコードの値から文字列を返す。
     /// キーコードを元にステータスを表示するときに使う
     pub fn from_keycode(&self, keycode: u16) -> Option<String> {
-        for (k, v) in &self.keycode_map {
-            if *v == keycode {
-                return Some(k.clone())
-            }
+        self.name_map.get(&keycode).cloned()
+    }
+
+    /// キーコードとその名前のリスト。キーコードの順に並び、
+    /// 名前は正式な名前、別名の順に並ぶ
+    pub fn entries(&self) -> Vec<(u16, Vec<String>)> {
+        let mut entries: Vec<(u16, Vec<String>)> = Vec::new();
+        let mut codes: Vec<u16> = self.name_map.keys().cloned().collect();
+        codes.sort();
+        for code in codes {
+            let names = self.list.iter()
+                .filter(|(_, c)| *c == code)
+                .map(|(n, _)| n.clone())
+                .collect();
+            entries.push((code, names));
         }
-        None
+        entries
     }
 }
 
@@ -576,4 +607,14 @@ fn test_keycode() {
     let keycode  = Keycode::new();
 
     println!("{}", keycode.from_keyword("UP").unwrap());
+
+    // 別名があるキーコードは常に同じ名前になる
+    assert_eq!(keycode.from_keyword("WiMax"), Some(KEY_WWAN));
+    assert_eq!(keycode.from_keycode(KEY_WWAN), Some("WWAN".to_string()));
+    let entries = keycode.entries();
+    assert!(entries.contains(&(KEY_WWAN, vec!["WWAN".to_string(), "WIMAX".to_string()])));
+    assert_eq!(entries.first().map(|e| e.0), Some(KEY_RESERVED));
+
+    assert!(is_modifier(KEY_RIGHTSHIFT));
+    assert!(!is_modifier(KEY_CAPSLOCK));
 }
\ No newline at end of file
@@ -1,8 +1,14 @@
 use std::cmp::Eq;
 use std::hash::Hash;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de;
 
 pub mod keycode;
 use self::keycode::Keycode;
+use super::options::RuleOptions;
+use super::options::split_options;
+use message::Error;
+use message::Msg;
 
 
 lazy_static! {
@@ -15,21 +21,26 @@ lazy_static! {
 pub enum Key {
     Raw(u16), // 変換される前のキー
     Con(u16), // 変換された後のキー (convert)
-    Rule(String) // ルール名
+    Rule(String), // ルール名
+    Var(String), // 仮想的な修飾キー ($NAV)。ルールの中だけで使い、入力はしない
 }
 
 impl Key {
     /// 文字列からKeyを作成する
-    pub fn from_str(s: &str) -> Result<Key, String> {
+    pub fn from_str(s: &str) -> Result<Key, Error> {
         match s.chars().next() {
             Some('\'') => match s.get(1..) {
                 Some(s) => match KEYCODE.from_keyword(s) {
                     Some(k) => return Ok(Key::Con(k)),
                     None => {
-                        return Err(format!("'{}'は無効なキーコードです", s))
+                        return Err(Msg::InvalidKeycode(s.to_string()).into())
                     }
                 },
-                None => return Err(format!("'{}'は無効なキーコードです", s))
+                None => return Err(Msg::InvalidKeycode(s.to_string()).into())
+            },
+            Some('$') => match s.get(1..) {
+                Some(n) if !n.is_empty() => Ok(Key::Var(n.to_string())),
+                _ => Err(Msg::InvalidKeycode(s.to_string()).into())
             },
             Some('@') => match s.get(1..) {
                 Some(s) => Ok(Key::Rule(s.to_string())),
@@ -37,9 +48,9 @@ impl Key {
             },
             Some(_) => match KEYCODE.from_keyword(s) {
                 Some(k) => return Ok(Key::Raw(k)),
-                None => return Err(format!("'{}'は無効なキーコードです", s))
+                None => return Err(Msg::InvalidKeycode(s.to_string()).into())
             },
-            None => return Err(format!("'{}'は無効なキーコードです", s))
+            None => return Err(Msg::InvalidKeycode(s.to_string()).into())
         }
     }
 
@@ -47,7 +58,16 @@ impl Key {
         match self {
             Key::Raw(n) => Some(*n),
             Key::Con(n) => Some(*n),
-            Key::Rule(_) => None
+            Key::Rule(_) | Key::Var(_) => None
+        }
+    }
+
+    /// ルールファイルに書ける形式の文字列に変換する。
+    /// from_strで元のKeyに戻すことができる
+    pub fn to_source(&self) -> String {
+        match self {
+            Key::Rule(s) => "@".to_string() + s,
+            _ => self.to_string()
         }
     }
 
@@ -61,13 +81,28 @@ impl Key {
                 "'".to_string() + &KEYCODE.from_keycode(*n)
                                           .unwrap_or("UNKNOWN".to_string())
             },
-            Key::Rule(s) => s.clone()
+            Key::Rule(s) => s.clone(),
+            Key::Var(s) => "$".to_string() + s,
         };
 
         s
     }
 }
 
+/// 設定ファイルではKeyをルールファイルと同じ形式の文字列で表す
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.to_source())
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Key, D::Error> {
+        let s = String::deserialize(d)?;
+        Key::from_str(s.trim()).map_err(de::Error::custom)
+    }
+}
+
 #[cfg(test)]
 mod test_key {
     use super::Keycode;
@@ -78,10 +113,10 @@ mod test_key {
         let keycode = Keycode::new();
 
         let mut s = "";
-        assert_eq!(Key::from_str(& mut s), Err(format!("'{}'は無効なキーコードです", "")));
+        assert_eq!(Key::from_str(& mut s).unwrap_err().code(), "E101");
 
         let mut s = "  ";
-        assert_eq!(Key::from_str(& mut s), Err(format!("'{}'は無効なキーコードです", "  ")));
+        assert_eq!(Key::from_str(& mut s).unwrap_err().code(), "E101");
 
         let mut s = "A";
         assert_eq!(Key::from_str(& mut s), Ok(Key::Raw(keycode.from_keyword("A").unwrap())));
@@ -94,16 +129,34 @@ mod test_key {
 
         let mut s = "'RIGHTSHIFT";
         assert_eq!(Key::from_str(& mut s), Ok(Key::Con(keycode.from_keyword("RIGHTSHIFT").unwrap())));
+
+        assert_eq!(Key::from_str("$NAV"), Ok(Key::Var("NAV".to_string())));
+        assert_eq!(Key::from_str("$NAV").unwrap().to_source(), "$NAV");
+        assert_eq!(Key::from_str("$").unwrap_err().code(), "E101");
     }
 }
 
 
+/// 何も入力しないルールの右側
+pub const NO_OUTPUT: &str = "_";
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
 /// KeyRule
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct KeyRule {
     pub k: Vec<Key>,
     pub v: Vec<Key>,
-    pub ove: bool
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ove: bool,
+    // kのキーがkの順番に押されたときだけマッチする (A > B -> 'C)
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ord: bool,
+    // ルールの後ろに[]で書くオプション
+    #[serde(default, skip_serializing_if = "RuleOptions::is_empty")]
+    pub options: RuleOptions,
 }
 
 impl KeyRule {
@@ -111,7 +164,9 @@ impl KeyRule {
         KeyRule {
             k: k,
             v: v,
-            ove: false
+            ove: false,
+            ord: false,
+            options: RuleOptions::default()
         }
     }
 
@@ -119,7 +174,9 @@ impl KeyRule {
         KeyRule {
             k: k,
             v: v,
-            ove
+            ove,
+            ord: false,
+            options: RuleOptions::default()
         }
     }
 
@@ -134,31 +191,44 @@ impl KeyRule {
 
     // 文字列からKeyRuleを作成する
     // boolは上書きするかどうか
-    pub fn from_str(string: &str) -> Result<KeyRule, String> {
+    pub fn from_str(string: &str) -> Result<KeyRule, Error> {
         let mut klist = Vec::new();
         let mut vlist = Vec::new();
         let mut s;
         let mut ove = false;
 
+        // 末尾の[]はオプション
+        let (string, options) = match split_options(string) {
+            (s, Some(o)) => (s, RuleOptions::parse(o)?),
+            (s, None) => (s, RuleOptions::default())
+        };
+
         if string.contains("->") {
             s = string.split("->");
         } else if string.contains("-!>") {
             s = string.split("-!>");
             ove = true;
         } else {
-            return Err(format!("'->' or '-!>' がありません"))
+            return Err(Msg::NoArrow.into())
         }
 
         let kstr = match s.next() {
             Some(kstr) => kstr,
-            None => return Err(format!("左側の値がありません"))
+            None => return Err(Msg::NoLeftSide.into())
         };
         let vstr = match s.next() {
             Some(vstr) => vstr,
-            None => return Err(format!("右側の値がありません"))
+            None => return Err(Msg::NoRightSide.into())
         };
 
-        for k in kstr.split("+").map(|k| k.trim()) {
+        // "A > B"のように>でつなぐと、押す順番もルールの条件になる
+        let ord = kstr.contains('>');
+        if ord && kstr.contains('+') {
+            return Err(Msg::MixedOrder.into())
+        }
+        let separator = if ord { '>' } else { '+' };
+
+        for k in kstr.split(separator).map(|k| k.trim()) {
             match Key::from_str(k) {
                 Ok(k) => {
                     klist.push(k);
@@ -167,7 +237,9 @@ impl KeyRule {
             }
         }
 
-        for v in vstr.split("+").map(|v| v.trim()) {
+        // "_"は何も入力しない
+        let no_output = vstr.trim() == NO_OUTPUT;
+        for v in vstr.split("+").map(|v| v.trim()).filter(|_| !no_output) {
             match Key::from_str(v) {
                 Ok(v) => {
                     vlist.push(v);
@@ -176,17 +248,48 @@ impl KeyRule {
             }
         }
 
-        if klist.len() == 0 || vlist.len() == 0 {
-            return Err(format!("左側または右側の値がありません"))
+        if klist.len() == 0 || (vlist.len() == 0 && !no_output) {
+            return Err(Msg::EmptySide.into())
         }
 
         Ok(KeyRule {
             k: klist,
             v: vlist,
-            ove
+            ove,
+            ord,
+            options
         })
     }
 
+    /// ルールファイルに書ける形式の文字列を、左側と矢印以降に分けて返す
+    pub fn source_parts(&self) -> (String, String) {
+        let join = |keys: &Vec<Key>, separator: &str| keys.iter()
+            .map(|k| k.to_source())
+            .collect::<Vec<String>>()
+            .join(separator);
+        let arrow = if self.ove { "-!>" } else { "->" };
+
+        let v = if self.v.is_empty() { NO_OUTPUT.to_string() } else { join(&self.v, " + ") };
+        let mut right = format!("{} {}", arrow, v);
+        if !self.options.is_empty() {
+            right += " ";
+            right += &self.options.to_source();
+        }
+
+        (join(&self.k, self.separator()), right)
+    }
+
+    /// ルールファイルに書ける形式の文字列に変換する
+    pub fn to_source(&self) -> String {
+        let (left, right) = self.source_parts();
+        format!("{} {}", left, right)
+    }
+
+    /// 左側のキーの区切り
+    fn separator(&self) -> &'static str {
+        if self.ord { " > " } else { " + " }
+    }
+
     /// ルールを文字列へ変換する
     pub fn to_string(&self) -> String {
         let mut s = String::new();
@@ -194,12 +297,15 @@ impl KeyRule {
         for (i, k) in self.k.iter().enumerate() {
             s += &k.to_string();
             if i != self.k.len()-1 {
-                s += " + ";
+                s += self.separator();
             } else {
                 s += " -> ";
             }
         }
 
+        if self.v.is_empty() {
+            s += NO_OUTPUT;
+        }
         for (i, v) in self.v.iter().enumerate() {
             s += &v.to_string();
             if i != self.v.len()-1 {
@@ -209,4 +315,26 @@ impl KeyRule {
 
         s
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test_key_rule {
+    use super::KeyRule;
+
+    #[test]
+    fn test_key_rule_from_str() {
+        let r = KeyRule::from_str("A > B -> 'C").unwrap();
+        assert!(r.ord);
+        assert_eq!(r.to_source(), "A > B -> 'C");
+        assert!(!KeyRule::from_str("A + B -> 'C").unwrap().ord);
+        assert_eq!(KeyRule::from_str("A > B + C -> 'D").unwrap_err().code(), "E109");
+
+        let r = KeyRule::from_str("INSERT -> _").unwrap();
+        assert!(r.v.is_empty());
+        assert_eq!(r.to_source(), "INSERT -> _");
+        assert_eq!(r.to_string(), "INSERT -> _");
+
+        let r = KeyRule::from_str("A -> 'B + 'C  [repeat=chord]").unwrap();
+        assert_eq!(r.v.len(), 2);
+        assert_eq!(r.to_source(), "A -> 'B + 'C [repeat=chord]");
+    }
+}
@@ -0,0 +1,245 @@
+//! キーが押されるたびにルールを探さなくて済むように、ルールを索引にしたもの。
+//!
+//! キーに番号を付け、キーごとにそのキーを左側に含むルールのリストを持つ。
+//! キーが押されるとそのルールの満たされたキーの数だけを増やし、すべて満たされた
+//! ルールを番号の小さい順に適用する。適用したルールの右側のキーも押されたものと
+//! して同じように扱う。
+//!
+//! 「まだ適用していないルールのうち、満たされている最初のルールを適用する」ことを
+//! 繰り返すので、ルールを先頭から探し直す方法と同じ結果になる。
+//!
+//! 押す順番が決められたルール(A > B -> 'C)は、キーが押された順番、
+//! つまり変換中のキーのリストの中の位置も比べる。
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+
+use super::key_rule::Key;
+use super::key_rule::KeyRule;
+
+
+/// 索引の中のルール。キーは番号にしてある
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct IndexedRule {
+    k: Vec<usize>, // 重複を除いた左側のキー
+    v: Vec<usize>,
+    ord: Option<Vec<usize>>, // 押す順番が決められていれば、その順番の左側のキー
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Matcher {
+    ids: HashMap<Key, usize>, // キーとその番号
+    keys: Vec<Key>,           // 番号からキーを引く
+    candidates: Vec<Vec<usize>>, // キーの番号ごとに、そのキーを左側に含むルール
+    rules: Vec<IndexedRule>,
+    always: Vec<usize>,       // 左側が空で、常に満たされているルール
+}
+
+/// 適用したルールと、変換後のキー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Matched {
+    pub rules: Vec<usize>, // 適用した順のルールの番号
+    pub keys: Vec<Key>,
+}
+
+impl Matcher {
+    pub fn new(list: &[KeyRule]) -> Matcher {
+        let mut m = Matcher::default();
+        for rule in list {
+            m.push(rule);
+        }
+        m
+    }
+
+    /// ルールを最後に追加する
+    pub fn push(&mut self, rule: &KeyRule) {
+        let i = self.rules.len();
+        let mut k: Vec<usize> = rule.k.iter().map(|k| self.intern(k)).collect();
+        let ord = if rule.ord { Some(k.clone()) } else { None };
+        k.sort_unstable();
+        k.dedup();
+        let v = rule.v.iter().map(|v| self.intern(v)).collect();
+
+        for id in &k {
+            self.candidates[*id].push(i);
+        }
+        if k.is_empty() {
+            self.always.push(i);
+        }
+        self.rules.push(IndexedRule { k, v, ord });
+    }
+
+    fn intern(&mut self, key: &Key) -> usize {
+        if let Some(id) = self.ids.get(key) {
+            return *id
+        }
+
+        let id = self.keys.len();
+        self.ids.insert(key.clone(), id);
+        self.keys.push(key.clone());
+        self.candidates.push(Vec::new());
+        id
+    }
+
+    /// 押されているキーにルールを適用する。
+    /// keysは押された順にする。同じキーが2回以上あれば最初のものだけを使う
+    pub fn run<'a, I>(&self, keys: I) -> Matched
+        where I: IntoIterator<Item = &'a Key>
+    {
+        let mut state = State {
+            counts: vec![0; self.rules.len()],
+            pos: vec![0; self.keys.len()],
+            ready: self.always.iter().map(|i| Reverse(*i)).collect(),
+        };
+        // 同じキーで満たされたキーの数が増えないようにする
+        let mut vkeys: Vec<Key> = Vec::new();
+        for k in keys {
+            if !vkeys.contains(k) {
+                vkeys.push(k.clone());
+            }
+        }
+
+        for (pos, k) in vkeys.iter().enumerate() {
+            if let Some(id) = self.ids.get(k) {
+                self.press(*id, pos, &mut state);
+            }
+        }
+
+        let mut matched = Vec::new();
+        while let Some(Reverse(i)) = state.ready.pop() {
+            matched.push(i);
+
+            // ルールの右側のキーを押したことにする。すでにあるものは除外する
+            for id in &self.rules[i].v {
+                if state.pos[*id] == 0 {
+                    vkeys.push(self.keys[*id].clone());
+                    self.press(*id, vkeys.len() - 1, &mut state);
+                }
+            }
+        }
+
+        // ルールの左側として使われたキーを削除する。
+        // ルールのキーにマッチしなかったキーはそのまま残る
+        let mut used = vec![false; self.keys.len()];
+        for i in &matched {
+            for id in &self.rules[*i].k {
+                used[*id] = true;
+            }
+        }
+        vkeys.retain(|k| !self.ids.get(k).is_some_and(|id| used[*id]));
+
+        Matched { rules: matched, keys: vkeys }
+    }
+
+    /// posはキーのリストの中の位置
+    fn press(&self, id: usize, pos: usize, state: &mut State) {
+        state.pos[id] = pos + 1;
+        for i in &self.candidates[id] {
+            state.counts[*i] += 1;
+            if state.counts[*i] != self.rules[*i].k.len() {
+                continue
+            }
+
+            // 順番が違えば、これ以上キーが増えてもマッチしない
+            if let Some(ord) = &self.rules[*i].ord {
+                if !ord.windows(2).all(|w| state.pos[w[0]] < state.pos[w[1]]) {
+                    continue
+                }
+            }
+            state.ready.push(Reverse(*i));
+        }
+    }
+}
+
+/// runの途中の状態
+struct State {
+    counts: Vec<usize>,   // ルールごとの満たされた左側のキーの数
+    pos: Vec<usize>,      // キーの番号ごとの、キーのリストの中の位置+1。押されていなければ0
+    ready: BinaryHeap<Reverse<usize>>, // 満たされていて、まだ適用していないルール
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::Matcher;
+    use super::super::key_rule::Key;
+    use super::super::key_rule::KeyRule;
+
+    /// ルールを先頭から探し直す、索引を使わない方法
+    fn naive(list: &[KeyRule], keys: &[Key]) -> (Vec<usize>, Vec<Key>) {
+        let mut vkeys: Vec<Key> = keys.to_vec();
+        let mut matched: Vec<usize> = Vec::new();
+
+        'outer: loop {
+            for (i, rule) in list.iter().enumerate() {
+                let pos: Option<Vec<usize>> = rule.k.iter()
+                    .map(|k| vkeys.iter().position(|v| v == k)).collect();
+                let in_order = |p: &Vec<usize>| !rule.ord || p.windows(2).all(|w| w[0] < w[1]);
+                if matched.contains(&i) || !pos.as_ref().is_some_and(in_order) {
+                    continue
+                }
+                matched.push(i);
+                for v in &rule.v {
+                    if !vkeys.contains(v) {
+                        vkeys.push(v.clone());
+                    }
+                }
+                continue 'outer
+            }
+            break
+        }
+
+        let used: Vec<&Key> = matched.iter().flat_map(|i| list[*i].k.iter()).collect();
+        vkeys.retain(|k| !used.contains(&k));
+        (matched, vkeys)
+    }
+
+    #[test]
+    fn test_same_as_naive() {
+        // 線形合同法で決まった乱数を作る
+        let mut seed: u32 = 1;
+        let mut rand = |n: u32| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) % n
+        };
+        let key = |rand: &mut dyn FnMut(u32) -> u32| match rand(3) {
+            0 => Key::Raw(rand(8) as u16),
+            1 => Key::Con(rand(8) as u16),
+            _ => Key::Raw(rand(4) as u16),
+        };
+
+        let mut list = Vec::new();
+        for _ in 0..60 {
+            let k = (0..rand(3) + 1).map(|_| key(&mut rand)).collect();
+            let v = (0..rand(2) + 1).map(|_| key(&mut rand)).collect();
+            let mut rule = KeyRule::new(k, v);
+            rule.ord = rand(4) == 0;
+            list.push(rule);
+        }
+        let matcher = Matcher::new(&list);
+
+        for _ in 0..500 {
+            let mut keys: Vec<Key> = Vec::new();
+            for _ in 0..rand(4) {
+                let k = Key::Raw(rand(8) as u16);
+                if !keys.contains(&k) {
+                    keys.push(k);
+                }
+            }
+            let m = matcher.run(&keys);
+            assert_eq!((m.rules, m.keys), naive(&list, &keys));
+        }
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        // 'Aが2つあっても、'A + Bの2つのキーが満たされたことにはしない
+        let list = vec![KeyRule::new(vec![Key::Con(1), Key::Raw(2)], vec![Key::Con(3)])];
+        let matcher = Matcher::new(&list);
+        let m = matcher.run(&[Key::Con(1), Key::Con(1)]);
+        assert_eq!((m.rules, m.keys), (Vec::<usize>::new(), vec![Key::Con(1)]));
+        let m = matcher.run(&[Key::Con(1), Key::Con(1), Key::Raw(2)]);
+        assert_eq!((m.rules, m.keys), (vec![0], vec![Key::Con(3)]));
+    }
+}
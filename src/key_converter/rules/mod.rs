@@ -2,24 +2,61 @@
 /// ファイルからルールのリストを作る。
 /// キーのリストを受け取り、ルールに合うように変換する。
 
-use std::collections::HashSet;
 use std::collections::HashMap;
-use std::iter::FromIterator;
 use std::io::Read;
 
 pub mod key_rule;
+pub mod config;
+pub mod syntax;
+pub mod matcher;
+pub mod options;
 use self::key_rule::KeyRule;
 use self::key_rule::Key;
-
-
-/// ルールの構造体
-#[derive(Debug, Clone, PartialEq)]
+use self::syntax::SyntaxTree;
+use self::syntax::Node;
+use self::matcher::Matcher;
+use self::matcher::Matched;
+use self::options::SetOptions;
+use self::options::Passthrough;
+use message::Error;
+use message::Msg;
+
+
+/// ルールの構造体。
+/// 作成したときにルールを索引にするので、listにはpushで追加する
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "RulesData")]
 pub struct Rules {
     name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     extend: Option<String>, // 継承するルール名
+    // ルール名の後ろに書くオプション。継承はしない
+    #[serde(default, skip_serializing_if = "SetOptions::is_empty")]
+    options: SetOptions,
+    list: Vec<KeyRule>,
+    #[serde(skip)]
+    matcher: Matcher,
+}
+
+/// 設定ファイルから読み込むときのRules
+#[derive(Deserialize)]
+struct RulesData {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    extend: Option<String>,
+    #[serde(default)]
+    options: SetOptions,
+    #[serde(default)]
     list: Vec<KeyRule>,
 }
 
+impl From<RulesData> for Rules {
+    fn from(data: RulesData) -> Rules {
+        Rules::from_vec(&data.name, data.extend, data.list).with_options(data.options)
+    }
+}
+
 impl Rules {
     pub fn from_vec(name: &str, 
                     extend: Option<String>, 
@@ -28,204 +65,233 @@ impl Rules {
         Rules {
             name: name.to_string(),
             extend,
+            options: SetOptions::default(),
+            matcher: Matcher::new(&v),
             list: v
         }
     }
 
+    pub fn with_options(mut self, options: SetOptions) -> Rules {
+        self.options = options;
+        self
+    }
+
     pub fn get_name(&self) -> &str {
         &self.name
     }
 
-    /// 再帰的に実行する関数。
-    /// 第一引数のkeysは現在押されているキーのリスト（仮想的なキーも含む）。
-    /// 第二引数のmatched_rulesはすでにマッチしているルールへの参照。同じルール
-    /// に複数回マッチしないようにするため。
-    fn filter_recursion<'a>(&'a self, 
-                            keys: &mut Vec<Key>, 
-                            matched_rules: &mut Vec<&'a KeyRule>) 
-    {
-        'outer: for key_rule in &self.list {
-            // matched_rulesに入っているルールを除外した上でサブセットかどうか
-            if matched_rules.contains(&key_rule) {
-                continue;
-            }
-            
-            for k in &key_rule.k {
-                if !keys.contains(&k) {
-                    continue 'outer;
-                }
-            }
-
-            // println!("MATCH: {:?} -> {:?}", key_rule.k, key_rule.v);
+    pub fn get_extend(&self) -> Option<&str> {
+        self.extend.as_deref()
+    }
 
-            // マッチしたルールをmatched_rulesに追加する
-            matched_rules.push(key_rule);
+    pub fn get_options(&self) -> &SetOptions {
+        &self.options
+    }
 
-            // マッチしたルールの値をすべてkeysに入れる
-            for v in &key_rule.v {
-                // すでにあるものは除外する
-                if !keys.contains(&v) {
-                    keys.push(v.clone());
-                }
-            }
+    pub fn get_list(&self) -> &Vec<KeyRule> {
+        &self.list
+    }
 
-            // 新しくkeysをセットしたので、それをもとに再帰的に呼び出す
-            self.filter_recursion(keys, matched_rules);
+    /// ルールを最後に追加する
+    pub fn push(&mut self, rule: KeyRule) {
+        self.matcher.push(&rule);
+        self.list.push(rule);
+    }
 
-            // いずれかにマッチした時点でループをやめる
-            break
-        }
+    // ルールを元に引数のKeysをvkeysに変換する。
+    // keysは押された順にする
+    pub fn filter<'a, I>(&self, keys: I) -> Vec<Key>
+        where I: IntoIterator<Item = &'a Key>
+    {
+        self.filter_steps(keys).keys
     }
-    
-    // ルールを元に引数のKeysをvkeysに変換する
-    pub fn filter(&self, keys: &HashSet<Key>) -> Vec<Key> {
-        let mut matched_rules = Vec::new();
-        // vkeysの初期値は、keysの値
-        let mut vkeys: Vec<Key> = Vec::from_iter(keys.iter().map(|k| k.clone()));
-        let mut result: Vec<Key> = Vec::new();
-
-        // vkeysとmatched_rulesの値をセットする
-        self.filter_recursion(&mut vkeys, &mut matched_rules);
-        let keys: Vec<Key> = matched_rules.iter().map(|r| r.k.clone()).flatten().collect();
-
-        // ルールのキーとして使われているのにvkeysに入っているキーを削除する
-        // また、ルールのキーにマッチしなかったキーはそのまま残る
-        for vk in vkeys {
-            if !keys.contains(&vk) {
-                result.push(vk.clone());
-            }
+
+    /// filterと同じように変換し、適用したルールの番号を適用した順に返す
+    pub fn filter_steps<'a, I>(&self, keys: I) -> Matched
+        where I: IntoIterator<Item = &'a Key>
+    {
+        if self.options.passthrough != Some(Passthrough::Block) {
+            return self.matcher.run(keys)
         }
-        
-        result
+
+        // どのルールにもマッチしなかった実際のキーは入力しない
+        let keys: Vec<&Key> = keys.into_iter().collect();
+        let mut matched = self.matcher.run(keys.iter().cloned());
+        matched.keys.retain(|k| !(matches!(k, Key::Raw(_)) && keys.contains(&k)));
+        matched
     }
 
     // ルールを元に引数のKeysをvkeysに変換し、それを文字列にする
-    pub fn filter_to_string(&self, keys: &HashSet<Key>) -> String {
-        // 最初はfilter関数と同じ処理
-        let mut matched_rules = Vec::new();
-        let mut vkeys: Vec<Key> = Vec::from_iter(keys.iter().map(|k| k.clone()));
-        let mut result: Vec<Key> = Vec::new();
-
-        self.filter_recursion(&mut vkeys, &mut matched_rules);
-        let keys: Vec<Key> = matched_rules.iter().map(|r| r.k.clone()).flatten().collect();
-
-        for vk in &vkeys {
-            if !keys.contains(&vk) {
-                result.push(vk.clone());
-            }
-        }
+    pub fn filter_to_string<'a, I>(&self, keys: I) -> String
+        where I: IntoIterator<Item = &'a Key>
+    {
+        let matched = self.filter_steps(keys);
 
         // ここから文字列へ変換してゆく
         let mut s = String::new();
 
-        //s += &matched_rules.iter().map(|r| r.to_string()).collect::<String>();
-
-        for (i, r) in matched_rules.iter().enumerate() {
-            s += &r.to_string();
-            if i != matched_rules.len()-1 {
+        for (i, r) in matched.rules.iter().enumerate() {
+            s += &self.list[*r].to_string();
+            if i != matched.rules.len()-1 {
                 s += " , ";
             } else {
                 s += "  :  ";
             }
         }
 
-        for (i, k) in result.iter().enumerate() {
+        for (i, k) in matched.keys.iter().enumerate() {
             s += &k.to_string();
-            if i != result.len()-1 {
+            if i != matched.keys.len()-1 {
                 s += " + ";
             }
         }
         
         s
     }
+
+    /// 引数のKeysにマッチしたルールを、適用した順に文字列にする
+    pub fn matched_rules<'a, I>(&self, keys: I) -> Vec<String>
+        where I: IntoIterator<Item = &'a Key>
+    {
+        self.filter_steps(keys).rules.iter().map(|r| self.list[*r].to_string()).collect()
+    }
 }
 
 /// 文字列からルールを作成する
 pub struct RulesParser {}
 
-/// 読み出したルールを一時的に保持する構造体
+/// 読み出したルールを一時的に保持する構造体。
+/// 行番号はルールファイル以外から読み出したときはNoneになる
 pub struct ParsedRules {
     name: String,
     extend: Option<String>,
-    line: usize,
-    rule_list: Vec<(usize, KeyRule)>,
+    options: SetOptions,
+    line: Option<usize>,
+    rule_list: Vec<(Option<usize>, KeyRule)>,
+}
+
+impl ParsedRules {
+    /// 継承を解決していないRulesから作成する
+    pub fn from_rules(rules: Rules) -> ParsedRules {
+        ParsedRules {
+            name: rules.name,
+            extend: rules.extend,
+            options: rules.options,
+            line: None,
+            rule_list: rules.list.into_iter().map(|r| (None, r)).collect(),
+        }
+    }
+
+    /// 継承を解決せずにRulesへ変換する
+    pub fn into_rules(self) -> Rules {
+        let list = self.rule_list.into_iter().map(|(_, r)| r).collect();
+        Rules::from_vec(&self.name, self.extend, list).with_options(self.options)
+    }
 }
 
 impl RulesParser {
-    pub fn parse<R: Read>(mut r: R) -> Result<HashMap<String, Rules>, String> {
+    pub fn parse<R: Read>(r: R) -> Result<HashMap<String, Rules>, Error> {
+        RulesParser::resolve(RulesParser::parse_sets(r)?)
+    }
+
+    /// エラーがあっても最後まで読んで、すべてのエラーを集める。
+    /// 返り値はエラーのなかったルールのリストと、行番号の順に並べたエラー
+    pub fn parse_all<R: Read>(mut r: R) -> (HashMap<String, Rules>, Vec<Error>) {
         let mut s = String::new();
+        let _ = r.read_to_string(&mut s);
+
+        let (tree, mut errors) = SyntaxTree::parse_all(&s);
+        let (rules_list, resolve_errors) =
+            RulesParser::resolve_all(RulesParser::sets_from_tree(tree));
+        errors.extend(resolve_errors);
+        errors.sort_by_key(|e| (e.line, e.rule.clone()));
+        (rules_list, errors)
+    }
+
+    /// ルールファイルを読み出す。継承の解決はしない
+    pub fn parse_sets<R: Read>(mut r: R) 
+        -> Result<HashMap<String, ParsedRules>, Error> 
+    {
+        let mut s = String::new();
+        let _ = r.read_to_string(&mut s);
+        Ok(RulesParser::sets_from_tree(SyntaxTree::parse(&s)?))
+    }
+
+    /// 構文木からルール名ごとのルールを取り出す
+    fn sets_from_tree(tree: SyntaxTree) -> HashMap<String, ParsedRules> {
         let mut parsed_rules_list = HashMap::new();
         let mut parsed_rules = ParsedRules {
             name: "".to_string(),
             extend: None,
-            line: 0,
+            options: SetOptions::default(),
+            line: Some(0),
             rule_list: Vec::new(),
         };
 
-        let _ = r.read_to_string(&mut s);
-
-        // コメントを削除して、不要な行を削除する
-        let lines = s.lines()
-            .map(|l| l.split('#').next().unwrap())
-            .map(|l| l.trim())
-            .enumerate()
-            .filter(|(_, l)| l.len() != 0);
-
-        for (i, l) in lines {
-            match l.chars().next().unwrap() {
-                // '@'が来たらparsed_rulesをparsed_rules_listに追加して、
+        // 空行とコメントは無視する
+        for line in tree.lines {
+            let i = line.number;
+            match line.node {
+                // ルール名が来たらparsed_rulesをparsed_rules_listに追加して、
                 // 新しくparsed_rulesを作成する
-                '@' => match l.get(1..) {
-                    Some(n) => {
-                        parsed_rules_list.insert(parsed_rules.name.clone(),
-                                                 parsed_rules);
-
-                        // nameを取得する
-                        let name = 
-                            n.split(':').next().unwrap().trim().to_string();
-
-                        // extendがあれば取得する
-                        let extend = if let Some(ex) = n.split(':').nth(1) {
-                            // "@"のみのときのルール名は空文字列
-                            Some(ex.trim().get(1..).unwrap_or("").to_string())
-                        } else {
-                            None
-                        };
-
-                        // parsed_rulesを新しくする
-                        parsed_rules = ParsedRules {
-                            name,
-                            extend,
-                            line: i,
-                            rule_list: Vec::new(),
-                        };
-                    },
-                    None => return Err(format!("ルール名がありません: line {}", i))
+                Node::Header { name, extend, options, .. } => {
+                    parsed_rules_list.insert(parsed_rules.name.clone(),
+                                             parsed_rules);
+
+                    // parsed_rulesを新しくする
+                    parsed_rules = ParsedRules {
+                        name,
+                        extend,
+                        options,
+                        line: Some(i),
+                        rule_list: Vec::new(),
+                    };
                 },
-                _ => match KeyRule::from_str(l) {
-                    Ok(k) => {
-                        parsed_rules.rule_list.push((i, k));
-                    },
-                    Err(e) => return Err(format!("{}: line {}", e, i+1))
-                }
+                Node::Rule { rule, .. } => {
+                    parsed_rules.rule_list.push((Some(i), rule));
+                },
+                Node::Blank | Node::Comment(_) => ()
             }
         }
 
         parsed_rules_list.insert(parsed_rules.name.clone(), parsed_rules);
 
+        parsed_rules_list
+    }
+
+    /// 継承を解決してRulesのリストを作る
+    pub fn resolve(parsed_rules_list: HashMap<String, ParsedRules>) 
+        -> Result<HashMap<String, Rules>, Error> 
+    {
+        let (rules_list, mut errors) = RulesParser::resolve_all(parsed_rules_list);
+        match errors.is_empty() {
+            true => Ok(rules_list),
+            false => Err(errors.remove(0))
+        }
+    }
+
+    /// 継承を解決してRulesのリストを作る。
+    /// エラーのあったルール名は飛ばして、すべてのエラーを集める
+    pub fn resolve_all(parsed_rules_list: HashMap<String, ParsedRules>) 
+        -> (HashMap<String, Rules>, Vec<Error>) 
+    {
         let mut rules_list = HashMap::new();
+        let mut errors = Vec::new();
         for key in parsed_rules_list.keys() {
             // 重複などのときはエラーを出し、上書きなどをしながら
             // ルールのリストを作る
             let mut list: Vec<KeyRule> = Vec::new();
             let rules = match RulesParser::get_rule_rec(key, vec![], &parsed_rules_list) {
                 Ok(r) => r,
-                Err(e) => return Err(format!("'@{}' {}", key, e))
+                Err(e) => {
+                    errors.push(e.with_rule(key));
+                    continue
+                }
             };
 
             'outer: for (i, r) in rules {
                 for rule in &mut list {
-                    if !rule.compare_k(&r.k) {
+                    if !rule.compare_k(&r.k) || rule.ord != r.ord {
                         continue
                     }
 
@@ -235,33 +301,59 @@ impl RulesParser {
                         continue 'outer
                     }
 
-                    // そうでなければエラーを返す
-                    return Err(format!("同じキーでルールを登録することはできません: line {}", i))
+                    // そうでなければエラーにする
+                    // 行番号がなければルール名を付ける
+                    errors.push(match i {
+                        Some(i) => Error::new(Msg::DuplicateRule).with_line(i + 1),
+                        None => Error::new(Msg::DuplicateRule).with_rule(key)
+                    });
+                    continue 'outer
                 }
 
                 list.push(r);
             }
 
             // Rulesを追加する
-            rules_list.insert(key.to_string(), Rules {
-                name: key.to_string(),
-                extend: parsed_rules_list.get(key).unwrap().extend.clone(),
-                list
-            });
+            let parsed_rules = parsed_rules_list.get(key).unwrap();
+            let rules = Rules::from_vec(key, parsed_rules.extend.clone(), list)
+                .with_options(parsed_rules.options.clone());
+            rules_list.insert(key.to_string(), rules);
         }
 
-        Ok(rules_list)
+        // デフォルトのルールは必ず存在する
+        if !rules_list.contains_key("") {
+            rules_list.insert(String::new(), Rules::from_vec("", None, Vec::new()));
+        }
+
+        // タイムアウトで戻るルールは存在しなければならない。
+        // ルールファイルならルール名の行を付ける
+        for (key, rules) in &rules_list {
+            if let Some(name) = &rules.options.timeout_rule {
+                if !rules_list.contains_key(name) {
+                    let e = Error::new(Msg::UnknownRuleName(name.clone())).with_rule(key);
+                    errors.push(match parsed_rules_list.get(key).and_then(|p| p.line) {
+                        Some(i) => e.with_line(i + 1),
+                        None => e
+                    });
+                }
+            }
+        }
+
+        // 継承したルールの重複は、継承したルール名の数だけ見つかる
+        errors.sort_by_key(|e| (e.line, e.rule.clone()));
+        errors.dedup();
+        (rules_list, errors)
     }
 
     /// 再帰的にルールを取得する
     fn get_rule_rec<'a>(name: &'a str, 
                     mut name_history: Vec<&'a str>,
                     parsed_rules_list: &'a HashMap<String, ParsedRules>) 
-                    -> Result<Vec<(usize, KeyRule)>, String>
+                    -> Result<Vec<(Option<usize>, KeyRule)>, Error>
     {
         // すでに同じ名前があったらエラーを返す
         if name_history.contains(&name) {
-            return Err("継承が循環しています".to_string())
+            return Err(Msg::CyclicExtend.into())
         }
 
         name_history.push(&name);
@@ -317,6 +409,7 @@ mod test {
         if let Ok(_) = RulesParser::parse("->".as_bytes()) { panic!() }
         if let Ok(_) = RulesParser::parse("a->".as_bytes()) { panic!() } 
         if let Ok(_) = RulesParser::parse("->mm".as_bytes()) { panic!() } 
+        assert_eq!(RulesParser::parse("@A [timeout_rule=@B]".as_bytes()).unwrap_err().code(), "E114");
 
         let r = RulesParser::parse("A -> B".as_bytes()).unwrap().remove("").unwrap();
         assert_eq!(r.list, vec![
@@ -341,10 +434,10 @@ mod test {
         B -> 'A
         "#.as_bytes()).unwrap();
         let mut rlist = HashMap::new();
-        rlist.insert("".to_string(), Rules { name: String::new(), extend: None, list: vec![
+        rlist.insert("".to_string(), Rules::from_vec("", None, vec![
             KeyRule::new(vec![Key::Raw(code.from_keyword("A").unwrap())], vec![Key::Con(code.from_keyword("B").unwrap())]),
             KeyRule::new(vec![Key::Raw(code.from_keyword("B").unwrap())], vec![Key::Con(code.from_keyword("A").unwrap())]),
-        ]});
+        ]));
         assert_eq!(r, rlist);
 
         let r = r#"
@@ -353,7 +446,8 @@ mod test {
           A -> 'A
           A -> 'C
         "#.as_bytes();
-        assert_eq!(RulesParser::parse(r), Err("同じキーでルールを登録することはできません: line 4".to_string()));
+        let e = RulesParser::parse(r).unwrap_err();
+        assert_eq!((e.code(), e.line), ("E107", Some(5)));
 
 
         let r = RulesParser::parse(r#"
@@ -363,12 +457,12 @@ mod test {
           A -!> 'C
         "#.as_bytes()).unwrap();
         let mut rlist = HashMap::new();
-        rlist.insert("".to_string(), Rules { name: String::new(), extend: None, list: vec![
+        rlist.insert("".to_string(), Rules::from_vec("", None, vec![
             KeyRule::new(vec![Key::Raw(code.from_keyword("A").unwrap())], vec![Key::Con(code.from_keyword("B").unwrap())]),
-        ]});
-        rlist.insert("test".to_string(), Rules { name: "test".to_string(), extend: Some("".to_string()), list: vec![
+        ]));
+        rlist.insert("test".to_string(), Rules::from_vec("test", Some("".to_string()), vec![
             KeyRule::with_ove(vec![Key::Raw(code.from_keyword("A").unwrap())], vec![Key::Con(code.from_keyword("C").unwrap())], true),
-        ]});
+        ]));
         assert_eq!(r, rlist);
 
         let r = RulesParser::parse(r#"
@@ -383,18 +477,18 @@ mod test {
             ENTER -> @RULE1
         "#.as_bytes()).unwrap();
         let mut rlist = HashMap::new();
-        rlist.insert("".to_string(), Rules { name: String::new(), extend: None, list: vec![
+        rlist.insert("".to_string(), Rules::from_vec("", None, vec![
             KeyRule::new(vec![Key::Raw(code.from_keyword("A").unwrap())], vec![Key::Con(code.from_keyword("B").unwrap())]),
             KeyRule::new(vec![Key::Raw(code.from_keyword("B").unwrap())], vec![Key::Con(code.from_keyword("A").unwrap())]),
-        ]});
-        rlist.insert("RULE1".to_string(), Rules { name: "RULE1".to_string(), extend: None, list: vec![
+        ]));
+        rlist.insert("RULE1".to_string(), Rules::from_vec("RULE1", None, vec![
             KeyRule::new(vec![Key::Raw(code.from_keyword("M").unwrap())], vec![Key::Con(code.from_keyword("N").unwrap())]),
             KeyRule::new(vec![Key::Raw(code.from_keyword("N").unwrap())], vec![Key::Con(code.from_keyword("M").unwrap())]),
-        ]});
-        rlist.insert("RULE2".to_string(), Rules { name: "RULE2".to_string(), extend: None, list: vec![
+        ]));
+        rlist.insert("RULE2".to_string(), Rules::from_vec("RULE2", None, vec![
             KeyRule::new(vec![Key::Raw(code.from_keyword("X").unwrap()), Key::Raw(code.from_keyword("Y").unwrap())], vec![Key::Con(code.from_keyword("Z").unwrap())]),
             KeyRule::new(vec![Key::Raw(code.from_keyword("ENTER").unwrap())], vec![Key::Rule("RULE1".to_string())]),
-        ]});
+        ]));
         assert_eq!(r, rlist);
 
         /* 継承のテスト */
@@ -404,10 +498,10 @@ mod test {
         @RULE3 : @
         "#.as_bytes()).unwrap();
         let mut rlist = HashMap::new();
-        rlist.insert("".to_string(), Rules { name: String::new(), extend: None, list: vec![]});
-        rlist.insert("RULE1".to_string(), Rules { name: "RULE1".to_string(), extend: None, list: vec![]});
-        rlist.insert("RULE2".to_string(), Rules { name: "RULE2".to_string(), extend: Some("RULE1".to_string()), list: vec![]});
-        rlist.insert("RULE3".to_string(), Rules { name: "RULE3".to_string(), extend: Some("".to_string()), list: vec![]});
+        rlist.insert("".to_string(), Rules::from_vec("", None, vec![]));
+        rlist.insert("RULE1".to_string(), Rules::from_vec("RULE1", None, vec![]));
+        rlist.insert("RULE2".to_string(), Rules::from_vec("RULE2", Some("RULE1".to_string()), vec![]));
+        rlist.insert("RULE3".to_string(), Rules::from_vec("RULE3", Some("".to_string()), vec![]));
         assert_eq!(r, rlist);
 
         let r = RulesParser::parse(r#"
@@ -419,17 +513,17 @@ mod test {
             N -> 'M
         "#.as_bytes()).unwrap();
         let mut rlist = HashMap::new();
-        rlist.insert("".to_string(), Rules { name: String::new(), extend: None, list: vec![]});
-        rlist.insert("RULE1".to_string(), Rules { name: "RULE1".to_string(), extend: None, list: vec![
+        rlist.insert("".to_string(), Rules::from_vec("", None, vec![]));
+        rlist.insert("RULE1".to_string(), Rules::from_vec("RULE1", None, vec![
             KeyRule::new(vec![Key::Raw(code.from_keyword("A").unwrap())], vec![Key::Con(code.from_keyword("B").unwrap())]),
             KeyRule::new(vec![Key::Raw(code.from_keyword("B").unwrap())], vec![Key::Con(code.from_keyword("A").unwrap())]),
-        ]});
-        rlist.insert("RULE2".to_string(), Rules { name: "RULE2".to_string(), extend: Some("RULE1".to_string()), list: vec![
+        ]));
+        rlist.insert("RULE2".to_string(), Rules::from_vec("RULE2", Some("RULE1".to_string()), vec![
             KeyRule::new(vec![Key::Raw(code.from_keyword("A").unwrap())], vec![Key::Con(code.from_keyword("B").unwrap())]),
             KeyRule::new(vec![Key::Raw(code.from_keyword("B").unwrap())], vec![Key::Con(code.from_keyword("A").unwrap())]),
             KeyRule::new(vec![Key::Raw(code.from_keyword("M").unwrap())], vec![Key::Con(code.from_keyword("N").unwrap())]),
             KeyRule::new(vec![Key::Raw(code.from_keyword("N").unwrap())], vec![Key::Con(code.from_keyword("M").unwrap())]),
-        ]});
+        ]));
         assert_eq!(r, rlist);
 
         let r = r#"
@@ -438,14 +532,33 @@ mod test {
         @RULE3 : @RULE1
         "#.as_bytes();
         // RULE1, RULE2, RULE3のいずれか
-        match RulesParser::parse(r) {
-            Err(e) if &e == "'@RULE1' 継承が循環しています" => (),
-            Err(e) if &e == "'@RULE2' 継承が循環しています" => (),
-            Err(e) if &e == "'@RULE3' 継承が循環しています" => (),
+        let e = RulesParser::parse(r).unwrap_err();
+        assert_eq!(e.code(), "E108");
+        match e.rule.as_deref() {
+            Some("RULE1") | Some("RULE2") | Some("RULE3") => (),
             _ => panic!()
         }
     }
 
+    #[test]
+    fn test_parse_all() {
+        let (rules_list, errors) = RulesParser::parse_all(r#"
+        A -> 'B
+        B -> 'XYZ
+        @SYMBOL [color=red]
+          C -> 'D
+        @X : @Y
+        @Y : @X
+        "#.as_bytes());
+
+        // エラーのある行は飛ばし、エラーのないルール名は読み出す
+        let codes: Vec<&str> = errors.iter().map(|e| e.code()).collect();
+        assert_eq!(codes, vec!["E108", "E108", "E101", "E112"]);
+        assert_eq!(rules_list[""].get_list().len(), 1);
+        assert_eq!(rules_list["SYMBOL"].get_list().len(), 1);
+        assert!(!rules_list.contains_key("X"));
+    }
+
     #[test]
     fn test_rule_filter() {
         #![allow(non_snake_case)]
@@ -480,25 +593,25 @@ mod test {
 
         let mut rule = RulesParser::parse("".as_bytes()).unwrap().remove("").unwrap();
         // A -> 'H
-        rule.list.push(KeyRule::new(vec![Key::Raw(KEY_A)], vec![Key::Con(KEY_H)]));
+        rule.push(KeyRule::new(vec![Key::Raw(KEY_A)], vec![Key::Con(KEY_H)]));
         assert_eq!(rule.filter(&hash![Key::Raw(KEY_A)]), vec![Key::Con(KEY_H)]);
 
         let mut rule = RulesParser::parse("".as_bytes()).unwrap().remove("").unwrap();
         // A -> 'H
-        rule.list.push(KeyRule::new(vec![Key::Raw(KEY_A)], vec![Key::Con(KEY_H)]));
+        rule.push(KeyRule::new(vec![Key::Raw(KEY_A)], vec![Key::Con(KEY_H)]));
         // 'H -> 'A
-        rule.list.push(KeyRule::new(vec![Key::Con(KEY_H)], vec![Key::Con(KEY_A)]));
+        rule.push(KeyRule::new(vec![Key::Con(KEY_H)], vec![Key::Con(KEY_A)]));
         assert_eq!(rule.filter(&hash![Key::Raw(KEY_A)]), vec![Key::Con(KEY_A)]);
 
         let mut rule = RulesParser::parse("".as_bytes()).unwrap().remove("").unwrap();
         // B -> 'I 
-        rule.list.push(KeyRule::new(vec![Key::Raw(KEY_B)], vec![Key::Con(KEY_I)]));
+        rule.push(KeyRule::new(vec![Key::Raw(KEY_B)], vec![Key::Con(KEY_I)]));
         // I -> 'B 
-        rule.list.push(KeyRule::new(vec![Key::Raw(KEY_I)], vec![Key::Con(KEY_B)]));
+        rule.push(KeyRule::new(vec![Key::Raw(KEY_I)], vec![Key::Con(KEY_B)]));
         // A -> 'C
-        rule.list.push(KeyRule::new(vec![Key::Raw(KEY_A)], vec![Key::Con(KEY_C)]));
+        rule.push(KeyRule::new(vec![Key::Raw(KEY_A)], vec![Key::Con(KEY_C)]));
         // 'B + 'C -> 'ENTER
-        rule.list.push(KeyRule::new(vec![Key::Con(KEY_B), Key::Con(KEY_C)], vec![Key::Con(KEY_ENTER)]));
+        rule.push(KeyRule::new(vec![Key::Con(KEY_B), Key::Con(KEY_C)], vec![Key::Con(KEY_ENTER)]));
 
         // push B
         assert_eq!(rule.filter(&hash![Key::Raw(KEY_B)]), vec![Key::Con(KEY_I)]);
@@ -515,15 +628,15 @@ mod test {
 
         let mut rule = RulesParser::parse("".as_bytes()).unwrap().remove("").unwrap();
         // B -> 'I 
-        rule.list.push(KeyRule::new(vec![Key::Raw(KEY_B)], vec![Key::Con(KEY_I)]));
+        rule.push(KeyRule::new(vec![Key::Raw(KEY_B)], vec![Key::Con(KEY_I)]));
         // ALT -> 'CTRL 
-        rule.list.push(KeyRule::new(vec![Key::Raw(KEY_ALT)], vec![Key::Con(KEY_CTRL)]));
+        rule.push(KeyRule::new(vec![Key::Raw(KEY_ALT)], vec![Key::Con(KEY_CTRL)]));
         // 'I + 'CTRL -> 'ENTER
-        rule.list.push(KeyRule::new(vec![Key::Con(KEY_I), Key::Con(KEY_CTRL)], vec![Key::Con(KEY_ENTER)]));
+        rule.push(KeyRule::new(vec![Key::Con(KEY_I), Key::Con(KEY_CTRL)], vec![Key::Con(KEY_ENTER)]));
         // C + 'J
-        rule.list.push(KeyRule::new(vec![Key::Raw(KEY_C)], vec![Key::Con(KEY_J)]));
+        rule.push(KeyRule::new(vec![Key::Raw(KEY_C)], vec![Key::Con(KEY_J)]));
         // 'J + 'CTRL -> 'SHIFT
-        rule.list.push(KeyRule::new(vec![Key::Con(KEY_J), Key::Con(KEY_CTRL)], vec![Key::Con(KEY_SHIFT)]));
+        rule.push(KeyRule::new(vec![Key::Con(KEY_J), Key::Con(KEY_CTRL)], vec![Key::Con(KEY_SHIFT)]));
         // push B + ALT = 'ENTER
         assert_eq!(rule.filter(&hash![Key::Raw(KEY_B), Key::Raw(KEY_ALT)]), vec![Key::Con(KEY_ENTER)]);
         // push C + ALT = 'SHIFT
@@ -532,12 +645,12 @@ mod test {
         assert_eq!(rule.filter(&hash![Key::Raw(KEY_B), Key::Raw(KEY_C), Key::Raw(KEY_ALT)]), vec![Key::Con(KEY_ENTER), Key::Con(KEY_SHIFT)]);
 
         // 'ENTER + 'SHIFT -> 'A
-        rule.list.push(KeyRule::new(vec![Key::Con(KEY_ENTER), Key::Con(KEY_SHIFT)], vec![Key::Con(KEY_A)]));
+        rule.push(KeyRule::new(vec![Key::Con(KEY_ENTER), Key::Con(KEY_SHIFT)], vec![Key::Con(KEY_A)]));
         // push B + C + ALT = 'A
         assert_eq!(rule.filter(&hash![Key::Raw(KEY_B), Key::Raw(KEY_C), Key::Raw(KEY_ALT)]), vec![Key::Con(KEY_A)]);
 
         // 'ENTER + 'SHIFT + BACKSPACE + TAB -> 'LEFT + 'RIGHT + 'UP + 'DOWN
-        rule.list.push(
+        rule.push(
             KeyRule::new(
                 vec![Key::Con(KEY_ENTER), Key::Con(KEY_SHIFT), Key::Raw(KEY_BACKSPACE), Key::Raw(KEY_TAB)], 
                 vec![Key::Con(KEY_LEFT), Key::Con(KEY_RIGHT), Key::Con(KEY_UP), Key::Con(KEY_DOWN)]
@@ -549,7 +662,7 @@ mod test {
         assert_eq!(rule.filter(&hash![Key::Raw(KEY_B), Key::Raw(KEY_C), Key::Raw(KEY_ALT), Key::Raw(KEY_BACKSPACE), Key::Raw(KEY_TAB)]), vec![Key::Con(KEY_A), Key::Con(KEY_LEFT), Key::Con(KEY_RIGHT), Key::Con(KEY_UP), Key::Con(KEY_DOWN)]);
 
         // 'A + 'LEFT + 'RIGHT + 'UP + 'DOWN -> 'F
-        rule.list.push(
+        rule.push(
             KeyRule::new(
                 vec![Key::Con(KEY_A), Key::Con(KEY_LEFT), Key::Con(KEY_RIGHT), Key::Con(KEY_UP), Key::Con(KEY_DOWN)],
                 vec![Key::Con(KEY_F)]
@@ -0,0 +1,297 @@
+//! ルール名とルールの後ろに[]で書くオプション。
+//!
+//! ```text
+//! @SYMBOL [repeat=off, repeat_delay=300, repeat_rate=30, timeout=5000, timeout_rule=@]
+//! @GAME [passthrough=block]
+//!   A -> 'X + 'Y [repeat=chord]
+//!   'LEFTCTRL + 'A -> 'BACKSPACE [latch]
+//! ```
+//!
+//! オプションは継承しない。
+
+use super::key_rule::Key;
+use key_converter::RepeatTiming;
+use message::Error;
+use message::Msg;
+
+
+/// キーリピートの方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Repeat {
+    On,    // 最後に押したキーをリピートする
+    Off,   // リピートしない
+    Chord, // 変換後のキーをすべて押し直す
+}
+
+impl Repeat {
+    fn from_str(s: &str) -> Option<Repeat> {
+        match s {
+            "on" => Some(Repeat::On),
+            "off" => Some(Repeat::Off),
+            "chord" => Some(Repeat::Chord),
+            _ => None
+        }
+    }
+
+    fn to_str(self) -> &'static str {
+        match self {
+            Repeat::On => "on",
+            Repeat::Off => "off",
+            Repeat::Chord => "chord",
+        }
+    }
+}
+
+/// どのルールにもマッチしなかったキーをどうするか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Passthrough {
+    Pass,  // そのまま入力する
+    Block, // 入力しない
+}
+
+impl Passthrough {
+    fn from_str(s: &str) -> Option<Passthrough> {
+        match s {
+            "pass" => Some(Passthrough::Pass),
+            "block" => Some(Passthrough::Block),
+            _ => None
+        }
+    }
+
+    fn to_str(self) -> &'static str {
+        match self {
+            Passthrough::Pass => "pass",
+            Passthrough::Block => "block",
+        }
+    }
+}
+
+/// ルール名の後ろに書くオプション
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct SetOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat: Option<Repeat>,
+    // リピートしない変換後のキー
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub norepeat: Vec<Key>,
+    // リピートが始まるまでのミリ秒。指定するとソフトウェアでリピートする
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_delay: Option<u64>,
+    // 1秒あたりのリピートの回数。指定するとソフトウェアでリピートする
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_rate: Option<u64>,
+    // キーを押さないままこのミリ秒が経つと、timeout_ruleのルールに戻る
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    // 戻るルール名。Noneなら""のルールに戻る
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_rule: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passthrough: Option<Passthrough>,
+}
+
+impl SetOptions {
+    pub fn parse(s: &str) -> Result<SetOptions, Error> {
+        let mut options = SetOptions::default();
+        for (name, value) in split(s) {
+            match name {
+                "repeat" => options.repeat = Some(parse_repeat(name, value)?),
+                "norepeat" => options.norepeat = parse_keys(name, value)?,
+                "repeat_delay" => options.repeat_delay = Some(parse_number(name, value)?),
+                "repeat_rate" => options.repeat_rate = Some(parse_number(name, value)?),
+                "timeout" => options.timeout = Some(parse_number(name, value)?),
+                "timeout_rule" => options.timeout_rule = Some(parse_rule_name(name, value)?),
+                "passthrough" => options.passthrough = Some(
+                    value.and_then(Passthrough::from_str).ok_or_else(|| invalid(name, value))?),
+                _ => return Err(Msg::UnknownRuleOption(name.to_string()).into())
+            }
+        }
+        options.validate()?;
+        Ok(options)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == SetOptions::default()
+    }
+
+    /// 型だけでは決まらない値を確かめる。
+    /// 設定ファイルから読み込んだときに、parseと同じエラーにするために使う
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(r) = self.repeat_rate.filter(|r| !RepeatTiming::is_valid_rate(*r)) {
+            return Err(invalid("repeat_rate", Some(&r.to_string())))
+        }
+        Ok(())
+    }
+
+    /// "[repeat=off]"のような文字列にする。オプションがなければ空文字列
+    pub fn to_source(&self) -> String {
+        let mut list = Vec::new();
+        if let Some(r) = self.repeat {
+            list.push(format!("repeat={}", r.to_str()));
+        }
+        if !self.norepeat.is_empty() {
+            list.push(format!("norepeat={}", join_keys(&self.norepeat)));
+        }
+        if let Some(d) = self.repeat_delay {
+            list.push(format!("repeat_delay={}", d));
+        }
+        if let Some(r) = self.repeat_rate {
+            list.push(format!("repeat_rate={}", r));
+        }
+        if let Some(t) = self.timeout {
+            list.push(format!("timeout={}", t));
+        }
+        if let Some(r) = &self.timeout_rule {
+            list.push(format!("timeout_rule=@{}", r));
+        }
+        if let Some(p) = self.passthrough {
+            list.push(format!("passthrough={}", p.to_str()));
+        }
+        bracket(list)
+    }
+}
+
+/// ルールの後ろに書くオプション
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct RuleOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat: Option<Repeat>,
+    // マッチしたあと、修飾キー以外の左側のキーを離すまで変換後のキーを押し続ける。
+    // Noneなら--latchに従う
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latch: Option<bool>,
+}
+
+impl RuleOptions {
+    pub fn parse(s: &str) -> Result<RuleOptions, Error> {
+        let mut options = RuleOptions::default();
+        for (name, value) in split(s) {
+            match name {
+                "repeat" => options.repeat = Some(parse_repeat(name, value)?),
+                "latch" => options.latch = Some(parse_switch(name, value)?),
+                _ => return Err(Msg::UnknownRuleOption(name.to_string()).into())
+            }
+        }
+        Ok(options)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == RuleOptions::default()
+    }
+
+    pub fn to_source(&self) -> String {
+        let mut list = Vec::new();
+        if let Some(r) = self.repeat {
+            list.push(format!("repeat={}", r.to_str()));
+        }
+        match self.latch {
+            Some(true) => list.push("latch".to_string()),
+            Some(false) => list.push("latch=off".to_string()),
+            None => ()
+        }
+        bracket(list)
+    }
+}
+
+/// 行の末尾の"[...]"を分ける。返り値はオプションより前の文字列と、[]の中身
+pub fn split_options(l: &str) -> (&str, Option<&str>) {
+    let l = l.trim_end();
+    match (l.ends_with(']'), l.rfind('[')) {
+        (true, Some(p)) => (&l[..p], Some(&l[p+1..l.len()-1])),
+        _ => (l, None)
+    }
+}
+
+/// "a=b, c"を(名前, 値)のリストにする
+fn split(s: &str) -> Vec<(&str, Option<&str>)> {
+    s.split(',')
+        .map(|o| o.trim())
+        .filter(|o| !o.is_empty())
+        .map(|o| match o.find('=') {
+            Some(p) => (o[..p].trim(), Some(o[p+1..].trim())),
+            None => (o, None)
+        })
+        .collect()
+}
+
+fn invalid(name: &str, value: Option<&str>) -> Error {
+    Msg::InvalidRuleOption(name.to_string(), value.unwrap_or("").to_string()).into()
+}
+
+fn parse_repeat(name: &str, value: Option<&str>) -> Result<Repeat, Error> {
+    value.and_then(Repeat::from_str).ok_or_else(|| invalid(name, value))
+}
+
+/// 値がなければon
+fn parse_switch(name: &str, value: Option<&str>) -> Result<bool, Error> {
+    match value {
+        None | Some("on") => Ok(true),
+        Some("off") => Ok(false),
+        _ => Err(invalid(name, value))
+    }
+}
+
+fn parse_number(name: &str, value: Option<&str>) -> Result<u64, Error> {
+    value.and_then(|v| v.parse().ok()).ok_or_else(|| invalid(name, value))
+}
+
+/// "@NAME"からルール名を取り出す
+fn parse_rule_name(name: &str, value: Option<&str>) -> Result<String, Error> {
+    value.and_then(|v| v.strip_prefix('@'))
+        .map(|v| v.trim().to_string())
+        .ok_or_else(|| invalid(name, value))
+}
+
+fn parse_keys(name: &str, value: Option<&str>) -> Result<Vec<Key>, Error> {
+    let value = value.ok_or_else(|| invalid(name, value))?;
+    value.split('+').map(|k| Key::from_str(k.trim())).collect()
+}
+
+fn join_keys(keys: &[Key]) -> String {
+    keys.iter().map(|k| k.to_source()).collect::<Vec<String>>().join(" + ")
+}
+
+fn bracket(list: Vec<String>) -> String {
+    if list.is_empty() {
+        String::new()
+    } else {
+        format!("[{}]", list.join(", "))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_options() {
+        assert_eq!(split_options("@SYM [repeat=off]"), ("@SYM ", Some("repeat=off")));
+        assert_eq!(split_options("A -> 'B"), ("A -> 'B", None));
+
+        let o = SetOptions::parse("repeat=off, norepeat='ENTER + 'TAB, repeat_delay=300").unwrap();
+        assert_eq!(o.repeat, Some(Repeat::Off));
+        assert_eq!(o.norepeat.len(), 2);
+        assert_eq!(o.to_source(), "[repeat=off, norepeat='ENTER + 'TAB, repeat_delay=300]");
+        assert_eq!(SetOptions::parse("").unwrap().to_source(), "");
+
+        let o = SetOptions::parse("timeout=3000, timeout_rule=@BASE").unwrap();
+        assert_eq!((o.timeout, o.timeout_rule.as_deref()), (Some(3000), Some("BASE")));
+        assert_eq!(o.to_source(), "[timeout=3000, timeout_rule=@BASE]");
+        assert_eq!(SetOptions::parse("timeout_rule=BASE").unwrap_err().code(), "E113");
+        // repeat_rateは1から1000まで
+        assert_eq!(SetOptions::parse("repeat_rate=1000").unwrap().repeat_rate, Some(1000));
+        assert_eq!(SetOptions::parse("repeat_rate=1001").unwrap_err().code(), "E113");
+        assert_eq!(SetOptions::parse("repeat_rate=0").unwrap_err().code(), "E113");
+        assert_eq!(SetOptions::parse("passthrough=block").unwrap().passthrough,
+                   Some(Passthrough::Block));
+
+        assert_eq!(RuleOptions::parse("repeat=chord").unwrap().repeat, Some(Repeat::Chord));
+        assert_eq!(RuleOptions::parse("latch").unwrap().to_source(), "[latch]");
+        assert_eq!(RuleOptions::parse("latch=off").unwrap().latch, Some(false));
+        assert_eq!(RuleOptions::parse("repeat=sometimes").unwrap_err().code(), "E113");
+        assert_eq!(RuleOptions::parse("color=red").unwrap_err().code(), "E112");
+    }
+}
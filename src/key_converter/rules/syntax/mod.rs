@@ -0,0 +1,364 @@
+//! ルールファイルの構文木。
+//! コメントや空行も含めて一行ずつ保持するので、元の文字列に戻すことも、
+//! 整形して出力することもできる。
+
+use super::Rules;
+use super::key_rule::KeyRule;
+use super::options::SetOptions;
+use super::options::split_options;
+use message::Error;
+use message::Msg;
+
+
+/// 一行分の構文
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Blank,
+    Comment(String), // '#'より後ろの文字列
+    Header {
+        name: String,
+        extend: Option<String>, // 継承するルール名
+        options: SetOptions,
+        comment: Option<String>,
+    },
+    Rule {
+        rule: KeyRule,
+        comment: Option<String>,
+    },
+}
+
+/// 構文と元の文字列
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub number: usize, // 0から始まる行番号
+    pub raw: String,
+    pub node: Node,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxTree {
+    pub lines: Vec<Line>,
+}
+
+// 整形したときのインデント
+const INDENT: &str = "  ";
+// 連続してもよい空行の数
+const MAX_BLANK_LINES: usize = 2;
+
+impl SyntaxTree {
+    pub fn parse(s: &str) -> Result<SyntaxTree, Error> {
+        let (tree, mut errors) = SyntaxTree::parse_all(s);
+        match errors.is_empty() {
+            true => Ok(tree),
+            false => Err(errors.remove(0))
+        }
+    }
+
+    /// エラーのある行を飛ばして最後まで読む。返り値は構文木とすべてのエラー
+    pub fn parse_all(s: &str) -> (SyntaxTree, Vec<Error>) {
+        let mut lines = Vec::new();
+        let mut errors = Vec::new();
+
+        for (i, raw) in s.lines().enumerate() {
+            let mut split = raw.splitn(2, '#');
+            let l = split.next().unwrap().trim();
+            let comment = split.next().map(|c| c.trim_end().to_string());
+
+            let node = match l.chars().next() {
+                None => match comment {
+                    Some(c) => Node::Comment(c),
+                    None => Node::Blank
+                },
+                Some('@') => match l.get(1..) {
+                    Some(n) => {
+                        // 末尾の[]はオプション
+                        let (n, options) = match split_options(n) {
+                            (n, Some(o)) => match SetOptions::parse(o) {
+                                Ok(o) => (n, o),
+                                // 後ろのルールが前のルール名に入らないように、ルール名は残す
+                                Err(e) => {
+                                    errors.push(e.with_line(i+1));
+                                    (n, SetOptions::default())
+                                }
+                            },
+                            (n, None) => (n, SetOptions::default())
+                        };
+
+                        // nameを取得する
+                        let name =
+                            n.split(':').next().unwrap().trim().to_string();
+
+                        // extendがあれば取得する
+                        // "@"のみのときのルール名は空文字列
+                        let extend = n.split(':').nth(1)
+                            .map(|ex| ex.trim().get(1..).unwrap_or("").to_string());
+
+                        Node::Header { name, extend, options, comment }
+                    },
+                    None => {
+                        errors.push(Error::new(Msg::NoRuleName).with_line(i+1));
+                        continue
+                    }
+                },
+                Some(_) => match KeyRule::from_str(l) {
+                    Ok(rule) => Node::Rule { rule, comment },
+                    Err(e) => {
+                        errors.push(e.with_line(i+1));
+                        continue
+                    }
+                }
+            };
+
+            lines.push(Line { number: i, raw: raw.to_string(), node });
+        }
+
+        (SyntaxTree { lines }, errors)
+    }
+
+    /// 継承を解決していないルールのリストから作成する。
+    /// ""のルールを先頭に、ルール名の前には空行を入れる
+    pub fn from_sets(sets: &[Rules]) -> SyntaxTree {
+        let mut nodes = Vec::new();
+        let default = sets.iter().filter(|r| r.name.is_empty());
+        let others = sets.iter().filter(|r| !r.name.is_empty());
+
+        for rules in default.chain(others) {
+            if !rules.name.is_empty() || rules.extend.is_some() || !rules.options.is_empty() {
+                if !nodes.is_empty() {
+                    nodes.push(Node::Blank);
+                }
+                nodes.push(Node::Header {
+                    name: rules.name.clone(),
+                    extend: rules.extend.clone(),
+                    options: rules.options.clone(),
+                    comment: None
+                });
+            }
+            for rule in &rules.list {
+                nodes.push(Node::Rule { rule: rule.clone(), comment: None });
+            }
+        }
+
+        // 元の文字列は整形したものにする
+        let lines = nodes.into_iter().enumerate()
+            .map(|(i, node)| Line {
+                number: i,
+                raw: SyntaxTree::node_to_source(&node),
+                node
+            })
+            .collect();
+
+        SyntaxTree { lines }
+    }
+
+    fn node_to_source(node: &Node) -> String {
+        match node {
+            Node::Blank => String::new(),
+            Node::Comment(c) => format!("#{}", c),
+            Node::Header { name, extend, options, .. } => header_to_source(name, extend, options),
+            Node::Rule { rule, .. } => rule.to_source(),
+        }
+    }
+
+    /// 元の文字列に戻す
+    pub fn to_source(&self) -> String {
+        let mut s = String::new();
+        for l in &self.lines {
+            s += &l.raw;
+            s += "\n";
+        }
+        s
+    }
+
+    /// 整形した文字列を返す。
+    /// ルール名の下のルールはインデントし、連続するルールは矢印の位置を揃える
+    pub fn format(&self) -> String {
+        // (インデントするかどうか, 構文)のリストを作る
+        let mut nodes: Vec<(bool, &Node)> = Vec::new();
+        let mut in_block = false;
+        let mut blank = 0;
+        for (i, l) in self.lines.iter().enumerate() {
+            match &l.node {
+                Node::Blank => {
+                    // 先頭の空行は出力しない
+                    blank += 1;
+                    if nodes.is_empty() || blank > MAX_BLANK_LINES {
+                        continue
+                    }
+                },
+                Node::Header { .. } => {
+                    in_block = true;
+                    blank = 0;
+                    nodes.push((false, &l.node));
+                    continue
+                },
+                Node::Comment(_) => {
+                    blank = 0;
+                    // ルール名の直前のコメントはルール名と同じ位置にする
+                    let before_header = self.lines[i+1..].iter()
+                        .find(|l| !matches!(l.node, Node::Comment(_)))
+                        .map(|l| matches!(l.node, Node::Header { .. }))
+                        .unwrap_or(false);
+                    nodes.push((in_block && !before_header, &l.node));
+                    continue
+                },
+                Node::Rule { .. } => blank = 0,
+            }
+            nodes.push((in_block, &l.node));
+        }
+
+        // 末尾の空行は出力しない
+        while let Some((_, Node::Blank)) = nodes.last() {
+            nodes.pop();
+        }
+
+        // 空行とルール名で区切られたルールごとに、左側の幅を求める
+        let mut widths = vec![0; nodes.len()];
+        let mut start = 0;
+        for i in 0..=nodes.len() {
+            let end = matches!(nodes.get(i),
+                Some((_, Node::Blank)) | Some((_, Node::Header { .. })) | None);
+            if !end {
+                continue
+            }
+
+            let width = nodes[start..i].iter()
+                .filter_map(|(_, n)| match n {
+                    Node::Rule { rule, .. } => Some(rule.source_parts().0.chars().count()),
+                    _ => None
+                })
+                .max()
+                .unwrap_or(0);
+            for w in &mut widths[start..i] {
+                *w = width;
+            }
+            start = i + 1;
+        }
+
+        let mut s = String::new();
+        for (i, (indent, node)) in nodes.iter().enumerate() {
+            let mut line = if *indent { INDENT.to_string() } else { String::new() };
+            let comment = match node {
+                Node::Blank => None,
+                Node::Comment(c) => {
+                    line += "#";
+                    line += c;
+                    None
+                },
+                Node::Header { name, extend, options, comment } => {
+                    line += &header_to_source(name, extend, options);
+                    comment.as_ref()
+                },
+                Node::Rule { rule, comment } => {
+                    let (left, right) = rule.source_parts();
+                    line += &format!("{:<width$} {}", left, right, width = widths[i]);
+                    comment.as_ref()
+                },
+            };
+
+            if let Some(c) = comment {
+                line += " #";
+                line += c;
+            }
+
+            s += line.trim_end();
+            s += "\n";
+        }
+
+        s
+    }
+}
+
+/// ルール名の行を文字列にする
+fn header_to_source(name: &str, extend: &Option<String>, options: &SetOptions) -> String {
+    let mut s = format!("@{}", name);
+    if let Some(e) = extend {
+        s += " : @";
+        s += e;
+    }
+    if !options.is_empty() {
+        s += " ";
+        s += &options.to_source();
+    }
+    s
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::SyntaxTree;
+    use super::super::RulesParser;
+
+    const SOURCE: &str = r#"
+
+# comment
+CapsLock->'LeftCtrl
+'leftctrl + 'a -> 'BackSpace    # trailing
+  F  -> 'A
+
+
+
+# header comment
+@RULE_1:@
+ J -!> 'B
+    # inner comment
+  LeftCtrl + Q -> @RULE_2
+
+@RULE_2 [ repeat = off ]
+"#;
+
+    const FORMATTED: &str = r#"# comment
+CAPSLOCK       -> 'LEFTCTRL
+'LEFTCTRL + 'A -> 'BACKSPACE # trailing
+F              -> 'A
+
+
+# header comment
+@RULE_1 : @
+  J            -!> 'B
+  # inner comment
+  LEFTCTRL + Q -> @RULE_2
+
+@RULE_2 [repeat=off]
+"#;
+
+    #[test]
+    fn test_format() {
+        let tree = SyntaxTree::parse(SOURCE).unwrap();
+        assert_eq!(tree.to_source(), SOURCE);
+
+        let formatted = tree.format();
+        assert_eq!(formatted, FORMATTED);
+
+        // 整形した結果をもう一度整形しても変わらない
+        assert_eq!(SyntaxTree::parse(&formatted).unwrap().format(), formatted);
+
+        // 整形しても同じルールになる
+        assert_eq!(RulesParser::parse(formatted.as_bytes()).unwrap(),
+                   RulesParser::parse(SOURCE.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn test_from_sets() {
+        let sets = ::key_converter::rules::config::read_sets(
+            FORMATTED.as_bytes(), ::key_converter::rules::config::Format::Text).unwrap();
+        let formatted = SyntaxTree::from_sets(&sets).format();
+        assert_eq!(formatted, "\
+CAPSLOCK       -> 'LEFTCTRL
+'LEFTCTRL + 'A -> 'BACKSPACE
+F              -> 'A
+
+@RULE_1 : @
+  J            -!> 'B
+  LEFTCTRL + Q -> @RULE_2
+
+@RULE_2 [repeat=off]
+");
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let e = SyntaxTree::parse("A -> 'B\nA -> 'XYZ").unwrap_err();
+        assert_eq!((e.code(), e.line), ("E101", Some(2)));
+    }
+}
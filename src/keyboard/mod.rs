@@ -10,10 +10,12 @@ use std::os::unix::io::AsRawFd;
 use std::mem;
 use std::thread;
 use std::sync::mpsc;
+use std::time::{Duration, Instant};
 use regex::Regex;
 use libc;
 use std::os::unix::io::RawFd;
 use std::ops::Drop;
+use key_converter::rules::key_rule::keycode::*;
 
 const DEVICESDIR: &str = "/dev/input/";
 
@@ -78,14 +80,92 @@ fn test_read_keyboard() {
     }
 }
 
+// Wakerが送るイベントの種類。evdevのイベントの種類とは重ならない
+pub const WAKE_TYPE: u16 = u16::MAX;
+
+/// デバイスから読んだそのままのイベント
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawEvent {
+    pub device: usize,  // Keyboard::devicesの番号
+    pub time: Duration, // カーネルが付けた時刻
+    pub ty: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+/// 別のスレッドからread_key_untilを終わらせる
+#[derive(Clone)]
+pub struct Waker {
+    sender: mpsc::Sender<RawEvent>,
+}
+
+impl Waker {
+    /// キーボードを開かずにWakerを作る。受け取る側も返す
+    #[cfg(test)]
+    pub fn channel() -> (Waker, mpsc::Receiver<RawEvent>) {
+        let (sender, receiver) = mpsc::channel();
+        (Waker { sender }, receiver)
+    }
+
+    pub fn wake(&self) {
+        self.sender.send(RawEvent { device: 0, time: Duration::ZERO, ty: WAKE_TYPE, code: 0, value: 0 });
+    }
+}
+
+/// ルールによらずkeymapを終了するキーの組み合わせのデフォルト
+pub const DEFAULT_ESCAPE: &[u16] = &[KEY_BACKSPACE, KEY_ESC, KEY_ENTER];
+
+/// read_key_untilの結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Key(u16, i32), // キーコードと状態
+    Wake,          // deadlineになったか、Wakerで起こされた
+    Escape,        // 終了するキーの組み合わせがすべて押された
+}
+
+/// 実際に押されているキーから、終了するキーの組み合わせを見つける
+struct Escape {
+    keys: Vec<u16>,
+    held: Vec<u16>,
+}
+
+impl Escape {
+    /// キーが押されるか離されたときに呼ぶ。
+    /// 組み合わせのキーが押されて、すべてのキーが押されているときにtrue
+    fn input(&mut self, code: u16, state: i32) -> bool {
+        match state {
+            1 if !self.held.contains(&code) => self.held.push(code),
+            0 => self.held.retain(|c| *c != code),
+            _ => ()
+        }
+        state == 1 && !self.keys.is_empty() && self.keys.contains(&code)
+            && self.keys.iter().all(|k| self.held.contains(k))
+    }
+}
+
+#[test]
+fn test_escape() {
+    let mut escape = Escape { keys: DEFAULT_ESCAPE.to_vec(), held: Vec::new() };
+    assert!(!escape.input(KEY_BACKSPACE, 1));
+    assert!(!escape.input(KEY_ESC, 1));
+    assert!(!escape.input(KEY_ESC, 0));
+    assert!(!escape.input(KEY_ENTER, 1));
+    assert!(!escape.input(KEY_A, 1));
+    assert!(escape.input(KEY_ESC, 1));
+}
+
 pub struct Keyboard {
-    receiver: mpsc::Receiver<(u16, u16, i32)>,
+    receiver: mpsc::Receiver<RawEvent>,
+    sender: mpsc::Sender<RawEvent>,
+    devices: Vec<String>, // デバイスのファイル名
+    last_device: usize,   // 最後にキーを読んだデバイスの番号
+    escape: Escape,
     keyboard_handles: Vec<thread::JoinHandle<()>>,
     raw_fd: Vec<RawFd>,
     options: u32,
 }
 
-fn read_keyboard(device: File, sender: mpsc::Sender<(u16, u16, i32)>) {
+fn read_keyboard(device: File, index: usize, sender: mpsc::Sender<RawEvent>) {
     let mut event = InputEvnet::new();
     let event_p = &mut event as *mut InputEvnet as *mut libc::c_void;
     loop{
@@ -94,7 +174,8 @@ fn read_keyboard(device: File, sender: mpsc::Sender<(u16, u16, i32)>) {
                 return;
             }
         }
-        sender.send((event.ty, event.code, event.value));
+        let time = Duration::new(event.time.tv_sec as u64, event.time.tv_usec as u32 * 1000);
+        sender.send(RawEvent { device: index, time, ty: event.ty, code: event.code, value: event.value });
     }
 }
 
@@ -115,19 +196,24 @@ impl Keyboard {
         let mut raw_fd: Vec<RawFd> = Vec::new();
         let (sender, receiver) = mpsc::channel();
 
-        for fname in get_keyboard_device_fnames() {
+        let devices = get_keyboard_device_fnames();
+        for (index, fname) in devices.iter().enumerate() {
             let device = File::open(fname)?;
             let child_sender = sender.clone();
 
             raw_fd.push(device.as_raw_fd());
             keyboard_handles.push(
-                thread::spawn(move || read_keyboard(device, child_sender))
+                thread::spawn(move || read_keyboard(device, index, child_sender))
             );
         }
 
         Ok(Keyboard {
             keyboard_handles: keyboard_handles,
             receiver: receiver,
+            sender,
+            devices,
+            last_device: 0,
+            escape: Escape { keys: DEFAULT_ESCAPE.to_vec(), held: Vec::new() },
             raw_fd: raw_fd,
             options: 0,
         })
@@ -149,7 +235,22 @@ impl Keyboard {
     }
 
     pub fn read(&self) -> (u16, u16, i32) {
-        self.receiver.recv().unwrap()
+        let e = self.receiver.recv().unwrap();
+        (e.ty, e.code, e.value)
+    }
+
+    /// キー以外も含めて、デバイスから読んだイベントをそのまま返す。
+    /// Wakerで起こされたときはNone
+    pub fn read_raw(&self) -> Option<RawEvent> {
+        match self.receiver.recv().unwrap() {
+            RawEvent { ty: WAKE_TYPE, .. } => None,
+            e => Some(e)
+        }
+    }
+
+    /// デバイスのファイル名。RawEvent::deviceの番号で引く
+    pub fn devices(&self) -> &[String] {
+        &self.devices
     }
 
     fn read_when_state_is(&self, ty: u16) -> (u16, u16, i32) {
@@ -163,6 +264,48 @@ impl Keyboard {
         return self.read_when_state_is(1);
     }
 
+    /// 終了するキーの組み合わせを設定する。ルールを適用する前に調べる
+    pub fn set_escape(&mut self, keys: Vec<u16>) {
+        self.escape.keys = keys;
+    }
+
+    /// deadlineまでキーの入力を待つ。
+    /// deadlineがNoneのときはキーが入力されるかWakerで起こされるまで待つ
+    pub fn read_key_until(&mut self, deadline: Option<Instant>) -> Event {
+        loop {
+            let event = match deadline {
+                Some(d) => match self.receiver.recv_timeout(d.saturating_duration_since(Instant::now())) {
+                    Ok(event) => event,
+                    Err(mpsc::RecvTimeoutError::Timeout) => return Event::Wake,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => panic!()
+                },
+                None => self.receiver.recv().unwrap()
+            };
+
+            match event {
+                RawEvent { device, ty: 1, code, value, .. } => {
+                    self.last_device = device;
+                    return if self.escape.input(code, value) {
+                        Event::Escape
+                    } else {
+                        Event::Key(code, value)
+                    }
+                },
+                RawEvent { ty: WAKE_TYPE, .. } => return Event::Wake,
+                _ => continue
+            }
+        }
+    }
+
+    /// 最後にキーを読んだデバイスのファイル名
+    pub fn last_device(&self) -> &str {
+        self.devices.get(self.last_device).map_or("", |d| d.as_str())
+    }
+
+    pub fn waker(&self) -> Waker {
+        Waker { sender: self.sender.clone() }
+    }
+
     pub fn read_syn(&self) -> (u16, u16, i32) {
         return self.read_when_state_is(0);
     }
@@ -0,0 +1,99 @@
+//! keymap keysで表示するキーの名前のリストと検索。
+//!
+//! 検索は大文字と小文字を区別せず、名前が同じもの、前方一致、部分一致、
+//! 文字が順に含まれるものの順に並べる。数字ならキーコードでも探す。
+
+use key_converter::rules::key_rule::keycode::Keycode;
+
+
+/// キーコードとその名前。namesは正式な名前、別名の順に並ぶ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub code: u16,
+    pub names: Vec<String>,
+}
+
+/// ルールで使えるすべてのキーの名前をキーコードの順に返す
+pub fn list() -> Vec<Entry> {
+    Keycode::new().entries().into_iter()
+        .map(|(code, names)| Entry { code, names })
+        .collect()
+}
+
+/// queryに近い順にキーを返す
+pub fn search(query: &str) -> Vec<Entry> {
+    let query = query.trim().to_uppercase();
+    let code = query.parse::<u16>().ok();
+
+    let mut found: Vec<(usize, Entry)> = list().into_iter()
+        .filter_map(|e| {
+            let by_code = if code == Some(e.code) { Some(0) } else { None };
+            e.names.iter().filter_map(|n| score(&query, n)).chain(by_code).min()
+                .map(|s| (s, e))
+        })
+        .collect();
+    found.sort_by_key(|(s, e)| (*s, e.code));
+    found.into_iter().map(|(_, e)| e).collect()
+}
+
+/// 小さいほど近い。queryの文字が順に含まれていなければNone
+fn score(query: &str, name: &str) -> Option<usize> {
+    if name == query {
+        return Some(0)
+    }
+    if name.starts_with(query) {
+        return Some(1)
+    }
+    if name.contains(query) {
+        return Some(2)
+    }
+
+    // queryの文字が順に含まれていれば、間に挟まる文字が少ないほど近い
+    let mut chars = name.char_indices();
+    let mut first = None;
+    let mut last = 0;
+    for q in query.chars() {
+        let (i, _) = chars.find(|(_, c)| *c == q)?;
+        first.get_or_insert(i);
+        last = i;
+    }
+    let span = last - first.unwrap_or(0) + 1;
+    Some(3 + span - query.chars().count())
+}
+
+/// "  30  A"のように1行に1つのキーを並べる。別名は括弧に入れる
+pub fn format(entries: &[Entry]) -> String {
+    let mut s = String::new();
+    for e in entries {
+        s += &format!("{:>5}  {}", e.code, e.names[0]);
+        if e.names.len() > 1 {
+            s += &format!(" ({})", e.names[1..].join(", "));
+        }
+        s += "\n";
+    }
+    s
+}
+
+
+#[cfg(test)]
+mod test {
+    use key_converter::rules::key_rule::keycode::*;
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        let codes = |q: &str| search(q).iter().map(|e| e.code).collect::<Vec<u16>>();
+
+        assert_eq!(codes("a")[0], KEY_A);
+        assert_eq!(codes("30")[0], KEY_A);
+        assert_eq!(codes("leftctrl"), vec![KEY_LEFTCTRL]);
+        // 部分一致は前方一致より後
+        let ctrl = codes("ctrl");
+        assert!(ctrl.starts_with(&[KEY_LEFTCTRL, KEY_RIGHTCTRL]) || ctrl.starts_with(&[KEY_RIGHTCTRL, KEY_LEFTCTRL]));
+        // 文字が順に含まれていればよい
+        assert!(codes("lctl").contains(&KEY_LEFTCTRL));
+        assert!(codes("zzzz").is_empty());
+
+        assert_eq!(format(&search("wimax")), "  246  WWAN (WIMAX)\n");
+    }
+}
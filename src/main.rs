@@ -2,113 +2,278 @@ extern crate libc;
 extern crate regex;
 #[macro_use]
 extern crate lazy_static;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
 use std::env;
-use std::fs::File;
+use std::time::{Instant, SystemTime};
 
 mod keyboard;
 mod virtual_keyboard;
 mod key_converter;
+mod message;
+mod command;
+mod interop;
+mod reload;
+mod control;
+mod shutdown;
+mod state_log;
+mod evlog;
+mod simulate;
+mod keys;
+mod explain;
 
-use keyboard::Keyboard;
+use keyboard::{Event, Keyboard};
+use key_converter::rules::key_rule::Key;
 use virtual_keyboard::*;
 use key_converter::KeyConverter;
+use key_converter::Output;
+use key_converter::RepeatTiming;
+use key_converter::{DEFAULT_REPEAT_DELAY, DEFAULT_REPEAT_RATE};
+use message::{Lang, Msg};
+use reload::Watcher;
+use control::{Command, Pause, Server, Status};
+use state_log::{Input, LogFormat};
 
 
-fn loop_keymap(kbd: Keyboard, 
-               mut vkbd: VirtualKeyboard, 
-               mut kc: KeyConverter, 
-               show_state: bool) 
-    {
-    let mut last_push = None;
+/// leave, push, repeatの順に仮想キーボードへ出力する
+fn emit(vkbd: &mut VirtualKeyboard, output: &Output) {
+    for l in &output.leave {
+        vkbd.leave(*l);
+    }
+    for p in &output.push {
+        vkbd.push(*p);
+    }
+    for r in &output.repeat {
+        vkbd.repeat(*r);
+    }
+}
 
-    loop {
-        let (_, read_code, state) = kbd.read_key();
-        
-        // 結果をoptionで受け取る
-        let (push, leave) = match state {
-            // push
-            1 => {
-                let (push, leave) = kc.push(read_code);
-                if let Some(p) = push.last() {
-                    last_push = Some(*p);
-                } else {
-                    last_push = None;
-                }
+/// キーの状態を表示する。
+/// readはキーボードから読んだキーで、ソフトウェアでリピートしたときなどはNone
+fn print_state(format: LogFormat, read: Option<Input>, output: &Output, kc: &mut KeyConverter) {
+    if format == LogFormat::Json {
+        println!("{}", state_log::to_json(SystemTime::now(), read, output, kc));
+        return
+    }
 
-                for l in &leave {
-                    vkbd.leave(*l);
-                }
+    // 現実世界のキーボードで入力された値を表示
+    print!("\t{:>15} | ", "kbd");
+    if let Some(read) = read {
+        match read.state {
+            0 => print!("leave "),
+            1 => print!("push "),
+            2 => print!("repeat "),
+            _ => ()
+        }
+        print!("{}", read.code);
+    }
+    println!();
 
-                for p in &push {
-                    vkbd.push(*p);
-                }
+    // 仮想的なキーボードで入力された値を表示
+    print!("\t{:>15} | ", "vkbd");
+    for l in &output.leave {
+        print!("leave {} ", l);
+    }
+    for p in &output.push {
+        print!("push {} ", p);
+    }
+    for r in &output.repeat {
+        print!("repeat {} ", r);
+    }
+    println!();
 
-                (Some(push), Some(leave))
-            },
-            // leave
-            0 => {
-                let leave = kc.leave(read_code);
+    // 押されているキーをルールに適用した結果を表示
+    let name = format!("@{}", kc.get_rules_name());
+    println!("\t{:>15} | {}", name, kc.filter_to_string());
 
-                for l in &leave {
-                    vkbd.leave(*l);
-                }
+    println!()
+}
+
+/// ルールファイルが変わっていれば読み込み直す。
+/// 読み込めなければエラーを表示して、今のルールを使い続ける。
+/// 返り値は離すキーのリスト
+fn reload(watcher: &mut Watcher, kc: &mut KeyConverter) -> Vec<u16> {
+    match watcher.check(Instant::now()) {
+        Some(Ok(rules_list)) => {
+            println!("{}", Msg::Reloaded(watcher.path().to_string()));
+            kc.replace_rules(rules_list)
+        },
+        Some(Err(e)) => {
+            print_error(e);
+            Vec::new()
+        },
+        None => Vec::new()
+    }
+}
 
-                (None, Some(leave))
+/// ソケットで受け取ったコマンドを実行する。
+/// 返り値は仮想キーボードへの出力で、コマンドを実行した順に並ぶ
+fn control(server: &mut Server,
+           kc: &mut KeyConverter,
+           watcher: &mut Watcher,
+           pause: &mut Pause) -> Vec<Output>
+    {
+    let mut outputs = Vec::new();
+    while let Some(request) = server.try_recv() {
+        let result = match request.command.clone() {
+            Command::Status => {
+                request.reply_status(&Status::new(kc, pause));
+                continue
             },
-            // repeat
-            2 => {
-                // 最後にvkbdにpushされたキーコードをrepeatする
-                if let Some(p) = last_push {
-                    vkbd.repeat(p);
+            Command::Subscribe => {
+                let status = Status::new(kc, pause);
+                server.subscribe(request, &status);
+                continue
+            },
+            Command::Switch(name) => match kc.select_rules(&name) {
+                Some(output) => {
+                    outputs.push(output);
+                    Ok(())
+                },
+                None => Err(Msg::UnknownRuleName(name).into())
+            },
+            Command::Pause => {
+                // 押されている変換後のキーは離して、再開するまで変換しない
+                if !pause.is_paused() {
+                    outputs.push(Output { leave: kc.release_all(), ..Output::default() });
                 }
-
-                (None, None)
+                pause.set(true);
+                Ok(())
             },
-            _ => panic!()
+            Command::Resume => {
+                pause.set(false);
+                Ok(())
+            },
+            Command::Reload => watcher.reload().map(|rules_list| {
+                println!("{}", Msg::Reloaded(watcher.path().to_string()));
+                outputs.push(Output { leave: kc.replace_rules(rules_list), ..Output::default() });
+            }),
         };
+        request.reply(result);
+    }
+    outputs
+}
 
-        // キーの状態を表示する
-        if show_state {
-            // 現実世界のキーボードで入力された値を表示
-            print!("\t{:>15} | ", "kbd");
-            match state {
-                0 => print!("leave "),
-                1 => print!("push "),
-                2 => print!("repeat "),
-                _ => ()
-            }
-            println!("{}", read_code);
+/// キーの入力を待つ時刻。ルールファイルを調べる時刻より後にはしない
+fn deadline(kc: &KeyConverter, watcher: &Watcher) -> Option<Instant> {
+    let poll = watcher.next_deadline();
+    Some(kc.next_deadline().map_or(poll, |d| d.min(poll)))
+}
 
-            // 仮想的なキーボードで入力された値を表示
-            print!("\t{:>15} | ", "vkbd");
-            for p in push.unwrap_or(Vec::new()) {
-                print!("push {} ", p);
-            }
-            for l in leave.unwrap_or(Vec::new()) {
-                print!("leave {} ", l);
+fn loop_keymap(mut kbd: Keyboard, 
+               mut vkbd: VirtualKeyboard, 
+               mut kc: KeyConverter, 
+               mut watcher: Watcher,
+               mut server: Option<Server>,
+               show_state: bool,
+               format: LogFormat) 
+    {
+    let mut pause = Pause::default();
+    while !shutdown::requested() {
+        let leave = reload(&mut watcher, &mut kc);
+        emit(&mut vkbd, &Output { leave, ..Output::default() });
+
+        if let Some(server) = server.as_mut() {
+            for output in control(server, &mut kc, &mut watcher, &mut pause) {
+                emit(&mut vkbd, &output);
             }
-            if state == 2 {
-                if let Some(p) = last_push {
-                    print!("repeat {} ", p);
+            server.publish(Status::new(&kc, &pause));
+        }
+
+        // ソフトウェアでのリピートやルールのtimeoutの時刻まで待つ
+        let (read_code, state) = match kbd.read_key_until(deadline(&kc, &watcher)) {
+            Event::Key(read_code, state) => (read_code, state),
+            Event::Escape => {
+                println!("{}", Msg::Escaped);
+                break
+            },
+            Event::Wake => {
+                let name = kc.get_rules_name().to_string();
+                let output = kc.tick(Instant::now());
+                emit(&mut vkbd, &output);
+                if show_state && (!output.is_empty() || name != kc.get_rules_name()) {
+                    print_state(format, None, &output, &mut kc);
                 }
+                continue
             }
-            println!();
+        };
 
-            // 押されているキーをルールに適用した結果を表示
-            let name = format!("@{}", kc.get_rules_name());
-            println!("\t{:>15} | {}", name, kc.filter_to_string());
+        // 一時停止中に押したキーはそのまま入力する
+        let output = match pause.input(read_code, state) {
+            Some(output) => output,
+            None => match state {
+                // push
+                1 => {
+                    let (push, leave) = kc.push(read_code);
+                    Output { leave, push, ..Output::default() }
+                },
+                // leave
+                0 => Output { leave: kc.leave(read_code), ..Output::default() },
+                // repeat
+                // どのキーをどうリピートするかはKeyConverterが決める
+                2 => kc.repeat(read_code),
+                _ => panic!()
+            }
+        };
+        emit(&mut vkbd, &output);
 
-            println!()
+        if show_state {
+            let read = Input { device: kbd.last_device(), code: read_code, state };
+            print_state(format, Some(read), &output, &mut kc);
         }
     }
+
+    close(kbd, vkbd);
+}
+
+/// 押されているキーを離し、キーボードのgrabを外してから仮想キーボードを削除する
+fn close(kbd: Keyboard, mut vkbd: VirtualKeyboard) {
+    vkbd.release_all();
+    drop(kbd);
+    drop(vkbd);
 }
 
 // 実際にvkbdでは入力しない
-fn loop_keymap_without_vkbd(kbd: Keyboard, mut kc: KeyConverter) {
-    loop {
-        let (_, read_code, state) = kbd.read_key();
+fn loop_keymap_without_vkbd(mut kbd: Keyboard,
+                            mut kc: KeyConverter,
+                            mut watcher: Watcher,
+                            mut server: Option<Server>,
+                            format: LogFormat)
+    {
+    let mut pause = Pause::default();
+    while !shutdown::requested() {
+        reload(&mut watcher, &mut kc);
+
+        if let Some(server) = server.as_mut() {
+            control(server, &mut kc, &mut watcher, &mut pause);
+            server.publish(Status::new(&kc, &pause));
+        }
+
+        // ルールのtimeoutの時刻まで待つ
+        let (read_code, state) = match kbd.read_key_until(deadline(&kc, &watcher)) {
+            Event::Key(read_code, state) => (read_code, state),
+            Event::Escape => {
+                println!("{}", Msg::Escaped);
+                break
+            },
+            Event::Wake => {
+                // 仮想的なキーボードで入力された値は表示しない
+                let name = kc.get_rules_name().to_string();
+                kc.tick(Instant::now());
+                if name != kc.get_rules_name() {
+                    print_state(format, None, &Output::default(), &mut kc);
+                }
+                continue
+            }
+        };
         
         // 結果をoptionで受け取る
+        if pause.input(read_code, state).is_some() {
+            continue
+        }
         match state {
             // push
             1 => {
@@ -123,42 +288,34 @@ fn loop_keymap_without_vkbd(kbd: Keyboard, mut kc: KeyConverter) {
             _ => panic!()
         }
 
-        // キーの状態を表示する
-        // 現実世界のキーボードで入力された値を表示
-        print!("\t{:>15} | ", "kbd");
-        match state {
-            0 => print!("leave "),
-            1 => print!("push "),
-            2 => print!("repeat "),
-            _ => ()
-        }
-        println!("{}", read_code);
-
-        // 仮想的なキーボードで入力された値を表示しない
-        println!("\t{:>15} | ", "vkbd");
-
-        // 押されているキーをルールに適用した結果を表示
-        let name = format!("@{}", kc.get_rules_name());
-        println!("\t{:>15} | {}", name, kc.filter_to_string());
-
-        println!()
+        // 仮想的なキーボードで入力された値は表示しない
+        let read = Input { device: kbd.last_device(), code: read_code, state };
+        print_state(format, Some(read), &Output::default(), &mut kc);
     }
 }
 
+/// "LEFTCTRL+RIGHTCTRL+ESC"のような終了するキーの組み合わせ。
+/// 変換後のキーやルール名は使えない
+fn parse_escape(s: &str) -> Option<Vec<u16>> {
+    s.split('+')
+        .map(|k| match Key::from_str(k.trim()) {
+            Ok(Key::Raw(c)) => Some(c),
+            _ => None
+        })
+        .collect()
+}
+
 fn print_help() {
-    println!("usage:");
-    println!("    keymap [options...] <rule>");
-    println!();
-    println!("arguments:");
-    println!("    <rule>    ルールを記述したファイルを指定します");
-    println!();
-    println!("options:");
-    println!("    -s, --show-state    実行中にキーの状態を出力します");
-    println!("    -r, --rule          ルールを適用しますが、実際に変換後のキーが入力されることはありません");
+    println!("{}", Msg::Help);
 }
 
 fn print_error<T: std::fmt::Display>(t: T) {
-    println!("Error: {}", t);
+    println!("{}: {}", Msg::ErrorLabel, t);
+}
+
+/// エラーコードを付けてエラーを表示する
+fn print_msg_error(m: Msg) {
+    print_error(message::Error::new(m));
 }
 
 fn main() {
@@ -166,9 +323,49 @@ fn main() {
     let mut show_state = false;
     let mut only_rule = false;
     let mut filename = None;
+    // どちらかを指定するとソフトウェアでリピートする
+    let mut repeat_delay = None;
+    let mut repeat_rate = None;
+    let mut latch = false;
+    let mut socket = control::DEFAULT_SOCKET.to_string();
+    let mut escape = None;
+    let mut format = LogFormat::Text;
+
+    message::set_lang(Lang::from_env());
+
+    // --langはどの位置にあっても先に処理する
+    let mut args = Vec::new();
+    let mut env_args = env::args().skip(1);
+    while let Some(arg) = env_args.next() {
+        let l = if arg == "--lang" {
+            env_args.next().unwrap_or_default()
+        } else if let Some(l) = arg.strip_prefix("--lang=") {
+            l.to_string()
+        } else {
+            args.push(arg);
+            continue
+        };
+
+        match Lang::from_str(&l) {
+            Some(l) => message::set_lang(l),
+            None => return print_msg_error(Msg::InvalidLang(l))
+        }
+    }
+
+    // サブコマンドを実行する
+    if let Some(name) = args.first() {
+        if command::is_command(name) {
+            if let Err(e) = command::run(name, &args[1..]) {
+                print_error(e);
+                std::process::exit(1);
+            }
+            return
+        }
+    }
 
     // 引数をパースする
-    for arg in env::args().skip(1) {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
         if arg.len() == 0 {
             continue
         }
@@ -177,8 +374,49 @@ fn main() {
             match arg.get(2..) {
                 Some("show-state") => show_state = true,
                 Some("rule") => only_rule = true,
+                Some("latch") => latch = true,
+                Some("escape") => match args.next().map(|v| parse_escape(&v)) {
+                    Some(Some(keys)) => escape = Some(keys),
+                    Some(None) => return print_msg_error(Msg::InvalidOption(arg)),
+                    None => return print_msg_error(
+                        Msg::MissingArgument(format!("{} <keys>", arg)))
+                },
+                // jsonは--show-stateも指定したことにする
+                Some("log-format") => match args.next().map(|v| (LogFormat::from_str(&v), v)) {
+                    Some((Some(f), _)) => {
+                        format = f;
+                        show_state |= f == LogFormat::Json;
+                    },
+                    Some((None, v)) => return print_msg_error(
+                        Msg::InvalidOption(format!("{} {}", arg, v))),
+                    None => return print_msg_error(
+                        Msg::MissingArgument(format!("{} <format>", arg)))
+                },
+                Some("socket") => match args.next() {
+                    Some(path) => socket = path,
+                    None => return print_msg_error(
+                        Msg::MissingArgument(format!("{} <path>", arg)))
+                },
+                Some(o @ "repeat-delay") | Some(o @ "repeat-rate") => {
+                    let n = match args.next() {
+                        Some(v) => match v.parse::<u64>() {
+                            Ok(n) => n,
+                            Err(_) => return print_msg_error(
+                                Msg::InvalidOption(format!("{} {}", arg, v)))
+                        },
+                        None => return print_msg_error(
+                            Msg::MissingArgument(format!("{} <n>", arg)))
+                    };
+                    if o == "repeat-delay" {
+                        repeat_delay = Some(n);
+                    } else if RepeatTiming::is_valid_rate(n) {
+                        repeat_rate = Some(n);
+                    } else {
+                        return print_msg_error(Msg::InvalidOption(format!("{} {}", arg, n)))
+                    }
+                },
                 _ => {
-                    print_error(format!("'{}'は無効なオプションです", arg));
+                    print_msg_error(Msg::InvalidOption(arg));
                     print_help();
                     return 
                 }
@@ -192,7 +430,7 @@ fn main() {
                     's' => show_state = true,
                     'r' => only_rule = true,
                     _ => {
-                        print_error(format!("'{}'は無効なオプションです", arg));
+                        print_msg_error(Msg::InvalidOption(arg));
                         print_help();
                         return 
                     }
@@ -204,48 +442,71 @@ fn main() {
         filename = Some(arg);
     }
 
-    let kc = match filename {
-        Some(f) => match File::open(f) {
-            Ok(f) => match KeyConverter::new(f) {
-                Ok(kc) => kc,
-                Err(e) => return print_error(e)
-            },
-            Err(_) => return print_error("ファイルが開けません")
+    // 拡張子によってルールの読み込み方を変える
+    let (mut kc, watcher) = match filename {
+        Some(f) => match reload::load_file(&f) {
+            Ok(rules_list) => (KeyConverter::from_rules_list(rules_list), Watcher::new(&f)),
+            Err(e) => return print_error(e)
         },
         None => {
-            print_error("ファイル名がありません");
+            print_msg_error(Msg::NoFileName);
             print_help();
             return 
         }
     };
 
+    if repeat_delay.is_some() || repeat_rate.is_some() {
+        kc.set_repeat_timing(Some(RepeatTiming::new(
+            repeat_delay.unwrap_or(DEFAULT_REPEAT_DELAY),
+            repeat_rate.unwrap_or(DEFAULT_REPEAT_RATE))));
+    }
+
+    kc.set_latch(latch);
+
     std::thread::sleep(wait_time);
     
-    let kbd;
+    let mut kbd;
     if only_rule {
         // grabしない
         kbd = match Keyboard::open() {
             Ok(kbd) => kbd,
-            Err(e) => { print_error(format!("{:?}", e.kind())); return; }
+            Err(e) => { print_msg_error(Msg::CannotOpenKeyboard(format!("{:?}", e.kind()))); return; }
         };
     } else {
         kbd = match Keyboard::open_and_grab() {
             Ok(kbd) => kbd,
-            Err(e) => { print_error(format!("{:?}", e.kind())); return; }
+            Err(e) => { print_msg_error(Msg::CannotOpenKeyboard(format!("{:?}", e.kind()))); return; }
         };
     }
 
+    if let Some(keys) = escape {
+        kbd.set_escape(keys);
+    }
+
+    // Ctrl-Cやkillで終了するときも、押されているキーを離す
+    shutdown::install(kbd.waker());
+    watcher.set_waker(kbd.waker());
+
     let vkbd = match VirtualKeyboard::new() {
         Some(vkbd) => vkbd,
         None => {
-            print_error("Can't create virtual_keyboard.");
+            print_msg_error(Msg::CannotCreateVirtualKeyboard);
             return;
         }
     };
 
+    // ソケットが開けなくても、keymap ctlが使えないだけで変換は続ける
+    let server = match Server::start(&socket, kbd.waker()) {
+        Ok(server) => Some(server),
+        Err(e) => {
+            print_error(e);
+            None
+        }
+    };
+
     if only_rule {
-        loop_keymap_without_vkbd(kbd, kc);
+        loop_keymap_without_vkbd(kbd, kc, watcher, server, format);
     } else {
-        loop_keymap(kbd, vkbd, kc, show_state);
+        loop_keymap(kbd, vkbd, kc, watcher, server, show_state, format);
     }
 }
\ No newline at end of file
@@ -0,0 +1,581 @@
+#![allow(dead_code)]
+//! ユーザーに表示するメッセージのカタログ。
+//! メッセージは英語と日本語を持ち、`set_lang`で選択された言語で表示される。
+//! エラーは言語に依存しないコード（E101など）で識別できる。
+
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// 表示する言語
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+    En,
+    Ja,
+}
+
+// 現在の言語。0がEn、1がJa
+static LANG: AtomicUsize = AtomicUsize::new(0);
+
+impl Lang {
+    /// "en", "ja", "ja_JP.UTF-8"のような文字列から言語を取得する
+    pub fn from_str(s: &str) -> Option<Lang> {
+        let s = s.to_lowercase();
+        if s.starts_with("ja") {
+            Some(Lang::Ja)
+        } else if s.starts_with("en") || s == "c" || s.starts_with("c.") || s == "posix" {
+            Some(Lang::En)
+        } else {
+            None
+        }
+    }
+
+    /// 環境変数（LC_ALL, LC_MESSAGES, LANGの順）から言語を取得する。
+    /// どれも設定されていなければ英語にする
+    pub fn from_env() -> Lang {
+        for name in &["LC_ALL", "LC_MESSAGES", "LANG"] {
+            match env::var(name) {
+                Ok(ref v) if !v.is_empty() => return Lang::from_str(v).unwrap_or(Lang::En),
+                _ => ()
+            }
+        }
+
+        Lang::En
+    }
+}
+
+pub fn set_lang(lang: Lang) {
+    let n = match lang {
+        Lang::En => 0,
+        Lang::Ja => 1,
+    };
+    LANG.store(n, Ordering::Relaxed);
+}
+
+pub fn lang() -> Lang {
+    match LANG.load(Ordering::Relaxed) {
+        1 => Lang::Ja,
+        _ => Lang::En,
+    }
+}
+
+/// 表示するメッセージのリスト
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Msg {
+    // 引数、実行時のエラー
+    InvalidOption(String),
+    NoFileName,
+    CannotOpenFile(String),
+    InvalidLang(String),
+    CannotOpenKeyboard(String),
+    CannotCreateVirtualKeyboard,
+    UnsupportedFormat(String),
+    CannotWriteFile(String),
+    MissingArgument(String),
+    NotFormatted(String),
+    InvalidImportSource(String),
+    InvalidExportTarget(String),
+    CannotOpenSocket(String, String),
+    CannotConnect(String),
+    UnknownControlCommand(String),
+    ControlFailed(String),
+    InvalidEvent(String),
+    CheckFailed(usize),
+
+    // ルールファイルのエラー
+    InvalidKeycode(String),
+    NoArrow,
+    NoLeftSide,
+    NoRightSide,
+    EmptySide,
+    NoRuleName,
+    DuplicateRule,
+    CyclicExtend,
+    MixedOrder,
+    InvalidConfig(String),
+    ImportSyntax(String),
+    UnknownRuleOption(String),
+    InvalidRuleOption(String, String),
+    UnknownRuleName(String),
+
+    // 他のツールの設定ファイルに変換するときのエラー
+    ExportChord(String),
+    ExportRuleSwitch(String),
+    ExportChained(String),
+    ExportMultipleKeys(String, &'static str),
+    ExportUnknownKey(String, &'static str),
+
+    // 他のツールの設定ファイルを変換するときの警告
+    ImportUnsupported(String),
+    ImportIgnored(String),
+    ImportUnknownKey(String),
+    ImportLayerKeyLeaks(String),
+    ImportDuplicate(String),
+
+    // エラーではないメッセージ
+    ErrorLabel,
+    WarningLabel,
+    Reloaded(String),
+    Escaped,
+    Recording(String),
+    PressKeys,
+    Help,
+}
+
+impl Msg {
+    /// エラーを識別するためのコード。
+    /// 翻訳とは違い、このコードは変更しない
+    pub fn code(&self) -> &'static str {
+        match self {
+            Msg::InvalidOption(_) => "E001",
+            Msg::NoFileName => "E002",
+            Msg::CannotOpenFile(_) => "E003",
+            Msg::InvalidLang(_) => "E004",
+            Msg::CannotOpenKeyboard(_) => "E010",
+            Msg::CannotCreateVirtualKeyboard => "E011",
+            Msg::UnsupportedFormat(_) => "E005",
+            Msg::CannotWriteFile(_) => "E006",
+            Msg::MissingArgument(_) => "E007",
+            Msg::NotFormatted(_) => "E008",
+            Msg::InvalidImportSource(_) => "E009",
+            Msg::InvalidExportTarget(_) => "E012",
+            Msg::CannotOpenSocket(..) => "E013",
+            Msg::CannotConnect(_) => "E014",
+            Msg::UnknownControlCommand(_) => "E015",
+            Msg::ControlFailed(_) => "E016",
+            Msg::InvalidEvent(_) => "E017",
+            Msg::CheckFailed(_) => "E018",
+
+            Msg::InvalidKeycode(_) => "E101",
+            Msg::NoArrow => "E102",
+            Msg::NoLeftSide => "E103",
+            Msg::NoRightSide => "E104",
+            Msg::EmptySide => "E105",
+            Msg::NoRuleName => "E106",
+            Msg::DuplicateRule => "E107",
+            Msg::CyclicExtend => "E108",
+            Msg::MixedOrder => "E109",
+            Msg::InvalidConfig(_) => "E110",
+            Msg::ImportSyntax(_) => "E111",
+            Msg::UnknownRuleOption(_) => "E112",
+            Msg::InvalidRuleOption(..) => "E113",
+            Msg::UnknownRuleName(_) => "E114",
+
+            Msg::ExportChord(_) => "E120",
+            Msg::ExportRuleSwitch(_) => "E121",
+            Msg::ExportChained(_) => "E122",
+            Msg::ExportMultipleKeys(_, _) => "E123",
+            Msg::ExportUnknownKey(_, _) => "E124",
+
+            Msg::ImportUnsupported(_) => "W001",
+            Msg::ImportIgnored(_) => "W002",
+            Msg::ImportUnknownKey(_) => "W003",
+            Msg::ImportLayerKeyLeaks(_) => "W004",
+            Msg::ImportDuplicate(_) => "W005",
+
+            Msg::ErrorLabel | Msg::WarningLabel | Msg::Reloaded(_) | Msg::Escaped | Msg::Recording(_) | Msg::PressKeys | Msg::Help => "",
+        }
+    }
+
+    /// 指定した言語の文字列に変換する
+    pub fn text(&self, lang: Lang) -> String {
+        let (en, ja) = match self {
+            Msg::InvalidOption(o) => (
+                format!("'{}' is not a valid option", o),
+                format!("'{}'は無効なオプションです", o)),
+            Msg::NoFileName => (
+                "no rule file given".to_string(),
+                "ファイル名がありません".to_string()),
+            Msg::CannotOpenFile(f) => (
+                format!("cannot open '{}'", f),
+                format!("'{}'が開けません", f)),
+            Msg::InvalidLang(l) => (
+                format!("'{}' is not a supported language (en, ja)", l),
+                format!("'{}'は対応していない言語です (en, ja)", l)),
+            Msg::CannotOpenKeyboard(e) => (
+                format!("cannot open the keyboard: {}", e),
+                format!("キーボードが開けません: {}", e)),
+            Msg::CannotCreateVirtualKeyboard => (
+                "cannot create the virtual keyboard".to_string(),
+                "仮想キーボードを作成できません".to_string()),
+            Msg::UnsupportedFormat(f) => (
+                format!("cannot write the {} format", f),
+                format!("{}形式には変換できません", f)),
+            Msg::CannotWriteFile(f) => (
+                format!("cannot write '{}'", f),
+                format!("'{}'に書き込めません", f)),
+            Msg::MissingArgument(a) => (
+                format!("{} is missing", a),
+                format!("{}がありません", a)),
+            Msg::NotFormatted(f) => (
+                format!("not formatted: {}", f),
+                format!("整形されていません: {}", f)),
+            Msg::InvalidImportSource(t) => (
+                format!("'{}' is not a supported tool (xmodmap, keyd, kmonad)", t),
+                format!("'{}'は対応していないツールです (xmodmap, keyd, kmonad)", t)),
+            Msg::InvalidExportTarget(t) => (
+                format!("'{}' is not a supported target (keyd, hwdb, xkb)", t),
+                format!("'{}'は対応していない変換先です (keyd, hwdb, xkb)", t)),
+            Msg::CannotOpenSocket(p, e) => (
+                format!("cannot open the control socket '{}': {}", p, e),
+                format!("操作用のソケット'{}'が開けません: {}", p, e)),
+            Msg::CannotConnect(p) => (
+                format!("cannot connect to '{}'. Is keymap running?", p),
+                format!("'{}'に接続できません。keymapは実行中ですか?", p)),
+            Msg::UnknownControlCommand(c) => (
+                format!("'{}' is not a control command (status, switch @<name>, pause, resume, reload, subscribe)", c),
+                format!("'{}'は操作用のコマンドではありません (status, switch @<name>, pause, resume, reload, subscribe)", c)),
+            Msg::ControlFailed(e) => (
+                format!("keymap failed: {}", e),
+                format!("keymapで失敗しました: {}", e)),
+            Msg::InvalidEvent(e) => (
+                format!("'{}' is not a valid event", e),
+                format!("'{}'は無効なイベントです", e)),
+            Msg::CheckFailed(n) => (
+                format!("found {} error(s)", n),
+                format!("{}個のエラーが見つかりました", n)),
+
+            Msg::InvalidKeycode(k) => (
+                format!("'{}' is not a valid key name", k),
+                format!("'{}'は無効なキーコードです", k)),
+            Msg::NoArrow => (
+                "'->' or '-!>' is missing".to_string(),
+                "'->' or '-!>' がありません".to_string()),
+            Msg::NoLeftSide => (
+                "the left side is missing".to_string(),
+                "左側の値がありません".to_string()),
+            Msg::NoRightSide => (
+                "the right side is missing".to_string(),
+                "右側の値がありません".to_string()),
+            Msg::EmptySide => (
+                "the left or right side is empty".to_string(),
+                "左側または右側の値がありません".to_string()),
+            Msg::NoRuleName => (
+                "the rule name is missing".to_string(),
+                "ルール名がありません".to_string()),
+            Msg::DuplicateRule => (
+                "a rule with the same keys is already registered".to_string(),
+                "同じキーでルールを登録することはできません".to_string()),
+            Msg::CyclicExtend => (
+                "the inheritance is cyclic".to_string(),
+                "継承が循環しています".to_string()),
+            Msg::MixedOrder => (
+                "'+' and '>' cannot be mixed on the left side".to_string(),
+                "左側で'+'と'>'を混ぜることはできません".to_string()),
+            Msg::InvalidConfig(e) => (
+                format!("invalid configuration: {}", e),
+                format!("設定ファイルが正しくありません: {}", e)),
+            Msg::ImportSyntax(e) => (
+                format!("cannot parse '{}'", e),
+                format!("'{}'を解析できません", e)),
+            Msg::UnknownRuleOption(o) => (
+                format!("unknown option '{}'", o),
+                format!("'{}'というオプションはありません", o)),
+            Msg::InvalidRuleOption(o, v) => (
+                format!("invalid value '{}' for option '{}'", v, o),
+                format!("オプション'{}'の値'{}'が正しくありません", o, v)),
+            Msg::UnknownRuleName(n) => (
+                format!("there is no rule named '@{}'", n),
+                format!("'@{}'というルールはありません", n)),
+
+            Msg::ExportChord(r) => (
+                format!("'{}' needs several keys pressed together, which only keymap can handle", r),
+                format!("'{}'は複数のキーの組み合わせを使うため、keymapでしか扱えません", r)),
+            Msg::ExportRuleSwitch(r) => (
+                format!("'{}' switches rules, which only keymap can handle", r),
+                format!("'{}'はルールを切り替えるため、keymapでしか扱えません", r)),
+            Msg::ExportChained(r) => (
+                format!("'{}' converts keys that other rules convert, which only keymap can handle", r),
+                format!("'{}'は他のルールと連続してキーを変換するため、keymapでしか扱えません", r)),
+            Msg::ExportMultipleKeys(r, t) => (
+                format!("'{}' outputs several keys, which {} cannot do", r, t),
+                format!("'{}'は複数のキーを出力するため、{}には変換できません", r, t)),
+            Msg::ExportUnknownKey(k, t) => (
+                format!("'{}' has no name in {}", k, t),
+                format!("'{}'は{}で使える名前がありません", k, t)),
+
+            Msg::ImportUnsupported(s) => (
+                format!("'{}' has no equivalent and was skipped", s),
+                format!("'{}'は変換できないため読み飛ばしました", s)),
+            Msg::ImportIgnored(s) => (
+                format!("'{}' is not needed and was ignored", s),
+                format!("'{}'は必要ないため無視しました", s)),
+            Msg::ImportUnknownKey(k) => (
+                format!("'{}' is not a known key", k),
+                format!("'{}'は不明なキーです", k)),
+            Msg::ImportLayerKeyLeaks(k) => (
+                format!("'{}' switches a layer while held, but is still sent when pressed alone", k),
+                format!("'{}'は押している間レイヤーを切り替えますが、単独で押すとそのまま入力されます", k)),
+            Msg::ImportDuplicate(r) => (
+                format!("'{}' is defined more than once; the first definition is used", r),
+                format!("'{}'は複数回定義されているため、最初の定義を使います", r)),
+
+            Msg::ErrorLabel => (
+                "Error".to_string(),
+                "エラー".to_string()),
+            Msg::WarningLabel => (
+                "Warning".to_string(),
+                "警告".to_string()),
+            Msg::Reloaded(f) => (
+                format!("reloaded '{}'", f),
+                format!("'{}'を読み込み直しました", f)),
+            Msg::Escaped => (
+                "the escape keys were pressed. keymap exits".to_string(),
+                "終了するキーが押されたので、keymapを終了します".to_string()),
+            Msg::Recording(f) => (
+                format!("recording the keyboard to '{}'. Press Ctrl-C to stop", f),
+                format!("キーボードの入力を'{}'に記録します。Ctrl-Cで終了します", f)),
+            Msg::PressKeys => (
+                "press keys to see their names. Press Ctrl-C to stop".to_string(),
+                "押したキーの名前を表示します。Ctrl-Cで終了します".to_string()),
+            Msg::Help => (HELP_EN.to_string(), HELP_JA.to_string()),
+        };
+
+        match lang {
+            Lang::En => en,
+            Lang::Ja => ja,
+        }
+    }
+}
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text(lang()))
+    }
+}
+
+const HELP_EN: &str = "\
+usage:
+    keymap [options...] <rule>
+    keymap convert <input> <output>
+    keymap fmt [--check] [--write] [<rule>...]
+    keymap import --from <tool> <file> [-o <output>]
+    keymap export --to <target> <rule> [--rule <name>] [-o <output>]
+    keymap ctl [--socket <path>] <command>
+    keymap record [--redact] <output>
+    keymap simulate [--latch] [--repeat-delay <ms>] [--repeat-rate <n>] <rule> <evlog>
+    keymap check <rule>...
+    keymap keys [--press] [<query>]
+    keymap explain [--rule <name>] <rule> <keys>
+
+arguments:
+    <rule>    the file describing the rules (.toml and .json are read as
+              configuration files). It is reloaded when it changes or when
+              keymap receives SIGHUP
+
+commands:
+    convert   convert between rule files and configuration files (.toml, .json)
+    fmt       print rule files in the canonical format (standard input when
+              no file is given). --check fails if a file is not formatted,
+              --write rewrites the files
+    import    translate the remaps, layers and modifier chords of an xmodmap,
+              keyd or KMonad configuration into rules. What cannot be
+              translated is reported as a warning
+    export    write the key remaps of a rule set (@ by default) as a keyd
+              configuration, a udev hwdb file or an XKB symbols file, for
+              machines where keymap cannot run. Only rules that replace one
+              key with another can be written
+    ctl       send a command to a running keymap through its control socket:
+              status, switch @<name>, pause, resume, reload or subscribe.
+              The results are printed as JSON lines
+    record    write the events read from the keyboards to an evlog file until
+              Ctrl-C, to attach to a bug report. --redact records keys other
+              than modifiers and function keys as UNKNOWN
+    simulate  convert the events of an evlog file, recorded or written by hand,
+              with the rules and print the keys the virtual keyboard would
+              send. No keyboard or root is needed
+    check     read rule files without opening the keyboard and print every
+              error, or the number of rules and the inheritance of each rule
+              set. Fails if there is an error
+    keys      print the key names rules accept with their codes and aliases,
+              the closest to <query> first. --press prints the name of each
+              key pressed on the keyboards until Ctrl-C
+    explain   apply a rule set (@ by default) to keys such as \"LeftCtrl + H\",
+              pressed in that order, and print each rule applied, the keys it
+              consumed and produced, and the keys that would be sent
+
+options:
+    -s, --show-state    print the state of the keys while running
+        --log-format <format>
+                        how --show-state prints: text, or json for one JSON
+                        object per event. json turns on --show-state
+    -r, --rule          apply the rules without sending the converted keys
+        --repeat-delay <ms>
+                        repeat keys in keymap instead of the keyboard, starting
+                        after <ms> milliseconds (default 500)
+        --repeat-rate <n>
+                        repeat keys in keymap instead of the keyboard, <n> times
+                        per second (default 30)
+        --latch         keep the keys a rule sends pressed until its keys other
+                        than modifiers are released, in every rule
+        --socket <path> the control socket for keymap ctl
+                        (default /run/keymap.sock)
+        --escape <keys> the keys that release every key, ungrab the keyboard
+                        and exit whatever the rules are
+                        (default BACKSPACE+ESC+ENTER)
+        --lang <lang>   language of the messages (en, ja)";
+
+const HELP_JA: &str = "\
+usage:
+    keymap [options...] <rule>
+    keymap convert <input> <output>
+    keymap fmt [--check] [--write] [<rule>...]
+    keymap import --from <tool> <file> [-o <output>]
+    keymap export --to <target> <rule> [--rule <name>] [-o <output>]
+    keymap ctl [--socket <path>] <command>
+    keymap record [--redact] <output>
+    keymap simulate [--latch] [--repeat-delay <ms>] [--repeat-rate <n>] <rule> <evlog>
+    keymap check <rule>...
+    keymap keys [--press] [<query>]
+    keymap explain [--rule <name>] <rule> <keys>
+
+arguments:
+    <rule>    ルールを記述したファイルを指定します (.tomlと.jsonは設定ファイル
+              として読み込みます)。ファイルが変わったときとSIGHUPを受け取った
+              ときに読み込み直します
+
+commands:
+    convert   ルールファイルと設定ファイル(.toml, .json)を相互に変換します
+    fmt       ルールファイルを整形して出力します (ファイルがなければ標準入力を
+              使います)。--checkは整形されていないファイルがあれば失敗し、
+              --writeはファイルを書き換えます
+    import    xmodmap, keyd, KMonadの設定ファイルのキーの置き換え、レイヤー、
+              修飾キーとの組み合わせをルールに変換します。変換できない部分は
+              警告を出力します
+    export    ルール(デフォルトは@)のキーの置き換えをkeydの設定ファイル、udevの
+              hwdbファイル、XKBのsymbolsファイルとして出力します。keymapを実行
+              できない環境で使います。1つのキーを置き換えるルールのみ変換できます
+    ctl       実行中のkeymapに操作用のソケットからコマンドを送ります: status,
+              switch @<name>, pause, resume, reload, subscribe。
+              結果はJSONの行で出力します
+    record    Ctrl-Cを押すまで、キーボードから読んだイベントをevlogファイルに
+              書き込みます。バグの報告に添付するために使います。--redactは
+              修飾キーとファンクションキー以外のキーをUNKNOWNとして記録します
+    simulate  記録したか手で書いたevlogファイルのイベントをルールで変換して、
+              仮想キーボードが入力するキーを出力します。キーボードもroot権限も
+              必要ありません
+    check     キーボードを開かずにルールファイルを読み、すべてのエラーか、
+              ルール名ごとのルールの数と継承を出力します。エラーがあれば
+              失敗します
+    keys      ルールで使えるキーの名前をキーコードと別名とともに、<query>に
+              近い順に出力します。--pressはCtrl-Cを押すまで、キーボードで
+              押したキーの名前を出力します
+    explain   \"LeftCtrl + H\"のようなキーがその順に押されたときに、ルール
+              (デフォルトは@)を適用する手順を出力します。適用したルールと、
+              そのルールが使ったキーと追加したキー、最後に入力されるキーを
+              表示します
+
+options:
+    -s, --show-state    実行中にキーの状態を出力します
+        --log-format <format>
+                        --show-stateの出力の形式を指定します: text、または1つの
+                        イベントを1つのJSONで出力するjson。jsonは--show-stateも
+                        指定します
+    -r, --rule          ルールを適用しますが、実際に変換後のキーが入力されることはありません
+        --repeat-delay <ms>
+                        キーボードではなくkeymapがキーをリピートします。<ms>ミリ秒
+                        後にリピートを始めます (デフォルトは500)
+        --repeat-rate <n>
+                        キーボードではなくkeymapがキーをリピートします。1秒に<n>回
+                        リピートします (デフォルトは30)
+        --latch         すべてのルールで、修飾キー以外の左側のキーを離すまで
+                        変換後のキーを押し続けます
+        --socket <path> keymap ctlで使う操作用のソケット
+                        (デフォルトは/run/keymap.sock)
+        --escape <keys> ルールによらず、すべてのキーを離し、キーボードのgrabを
+                        外して終了するキーの組み合わせ
+                        (デフォルトはBACKSPACE+ESC+ENTER)
+        --lang <lang>   メッセージの言語を指定します (en, ja)";
+
+
+/// コードと、エラーが発生した場所を持つエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub msg: Msg,
+    pub line: Option<usize>,
+    pub rule: Option<String>, // エラーが発生したルール名
+    pub file: Option<String>, // エラーが発生したファイル名
+}
+
+impl Error {
+    pub fn new(msg: Msg) -> Error {
+        Error {
+            msg,
+            line: None,
+            rule: None,
+            file: None,
+        }
+    }
+
+    pub fn with_line(mut self, line: usize) -> Error {
+        self.line = Some(line);
+        self
+    }
+
+    pub fn with_rule(mut self, rule: &str) -> Error {
+        self.rule = Some(rule.to_string());
+        self
+    }
+
+    pub fn with_file(mut self, file: &str) -> Error {
+        self.file = Some(file.to_string());
+        self
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.msg.code()
+    }
+}
+
+impl From<Msg> for Error {
+    fn from(msg: Msg) -> Error {
+        Error::new(msg)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] ", self.code())?;
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file)?;
+        }
+        if let Some(rule) = &self.rule {
+            write!(f, "'@{}' ", rule)?;
+        }
+        write!(f, "{}", self.msg)?;
+        if let Some(line) = self.line {
+            write!(f, ": line {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::Lang;
+    use super::Msg;
+    use super::Error;
+
+    #[test]
+    fn test_lang_from_str() {
+        assert_eq!(Lang::from_str("ja"), Some(Lang::Ja));
+        assert_eq!(Lang::from_str("ja_JP.UTF-8"), Some(Lang::Ja));
+        assert_eq!(Lang::from_str("en_US.UTF-8"), Some(Lang::En));
+        assert_eq!(Lang::from_str("C"), Some(Lang::En));
+        assert_eq!(Lang::from_str("fr"), None);
+    }
+
+    #[test]
+    fn test_msg_text() {
+        let m = Msg::InvalidKeycode("X".to_string());
+        assert_eq!(m.code(), "E101");
+        assert_eq!(m.text(Lang::En), "'X' is not a valid key name");
+        assert_eq!(m.text(Lang::Ja), "'X'は無効なキーコードです");
+
+        let e = Error::new(Msg::DuplicateRule).with_line(4);
+        assert_eq!(e.code(), "E107");
+        assert_eq!(e.line, Some(4));
+    }
+}
@@ -0,0 +1,145 @@
+//! 実行中にルールファイルを読み込み直す。
+//!
+//! SIGHUPを受け取ったときと、ルールファイルの更新時刻が変わったときに読み込み直す。
+//! SIGHUPはすぐに、更新時刻はPOLL_INTERVALごとに調べる。
+//! キーボードはgrabしたままにする。
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use libc;
+
+use keyboard::Waker;
+use key_converter::rules::Rules;
+use key_converter::rules::config;
+use key_converter::rules::config::Format;
+use message::Error;
+use message::Msg;
+use shutdown;
+
+
+// ルールファイルの更新時刻を調べる間隔
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// シグナルハンドラから書き込む
+static SIGHUP_RECEIVED: AtomicBool = AtomicBool::new(false);
+static SIGHUP_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    SIGHUP_RECEIVED.store(true, Ordering::SeqCst);
+    shutdown::notify(SIGHUP_FD.load(Ordering::SeqCst));
+}
+
+/// ルールファイルを読み込む
+pub fn load_file(path: &str) -> Result<HashMap<String, Rules>, Error> {
+    let r = File::open(path).map_err(|_| Error::new(Msg::CannotOpenFile(path.to_string())))?;
+    config::load(r, Format::from_path(path)).map_err(|e| e.with_file(path))
+}
+
+pub struct Watcher {
+    path: String,
+    modified: Option<SystemTime>, // 最後に読み込んだときの更新時刻
+    next_poll: Instant,
+}
+
+impl Watcher {
+    /// SIGHUPのハンドラを設定して、ルールファイルの監視を始める
+    pub fn new(path: &str) -> Watcher {
+        unsafe {
+            libc::signal(libc::SIGHUP, on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+
+        Watcher {
+            path: path.to_string(),
+            modified: modified(path),
+            next_poll: Instant::now() + POLL_INTERVAL,
+        }
+    }
+
+    /// SIGHUPを受け取ったら、次に調べる時刻を待たずにwakerでメインのループを起こす
+    pub fn set_waker(&self, waker: Waker) {
+        if let Some(fd) = shutdown::wake_pipe(waker) {
+            SIGHUP_FD.store(fd, Ordering::SeqCst);
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// 次に更新時刻を調べる時刻
+    pub fn next_deadline(&self) -> Instant {
+        self.next_poll
+    }
+
+    /// SIGHUPを受け取ったか更新時刻が変わっていれば読み込み直す。
+    /// 読み込み直さなかったときはNone
+    pub fn check(&mut self, now: Instant) -> Option<Result<HashMap<String, Rules>, Error>> {
+        let signaled = SIGHUP_RECEIVED.swap(false, Ordering::SeqCst);
+        if !signaled && now < self.next_poll {
+            return None
+        }
+        self.next_poll = now + POLL_INTERVAL;
+
+        // ファイルが一時的になくなっているときは、次に調べるときまで待つ
+        let m = modified(&self.path);
+        if !signaled && (m.is_none() || m == self.modified) {
+            return None
+        }
+
+        Some(self.reload())
+    }
+
+    /// 更新時刻によらず読み込み直す
+    pub fn reload(&mut self) -> Result<HashMap<String, Rules>, Error> {
+        self.modified = modified(&self.path);
+        load_file(&self.path)
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::{Duration, Instant};
+    use libc;
+    use keyboard::{Waker, WAKE_TYPE};
+    use super::Watcher;
+    use super::on_sighup;
+
+    #[test]
+    fn test_watcher() {
+        let path = ::std::env::temp_dir().join(format!("keymap-reload-{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "A -> 'B\n").unwrap();
+
+        let mut w = Watcher::new(path);
+        let t = Instant::now();
+        assert!(w.check(t).is_none());
+        assert!(w.check(t + Duration::from_secs(2)).is_none());
+
+        // 更新時刻が変われば読み込み直す。エラーもそのまま返す
+        fs::write(path, "A -> 'XYZ\n").unwrap();
+        let later = fs::metadata(path).unwrap().modified().unwrap() + Duration::from_secs(10);
+        fs::File::options().write(true).open(path).unwrap().set_modified(later).unwrap();
+        let e = w.check(t + Duration::from_secs(4)).unwrap().unwrap_err();
+        assert_eq!(e.code(), "E101");
+        assert!(w.check(t + Duration::from_secs(6)).is_none());
+
+        // SIGHUPを受け取るとwakerで起こし、次に調べる時刻を待たずに読み込み直す
+        let (waker, receiver) = Waker::channel();
+        w.set_waker(waker);
+        on_sighup(libc::SIGHUP);
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap().ty, WAKE_TYPE);
+        assert!(w.check(t + Duration::from_secs(6)).is_some());
+        assert!(w.check(t + Duration::from_secs(6)).is_none());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,103 @@
+//! SIGINTとSIGTERMを受け取ったときに、イベントループの中で終了する。
+//!
+//! シグナルハンドラではパイプに書き込むだけにして、
+//! パイプを読むスレッドがWakerでメインのループを起こす。
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread;
+use libc;
+
+use keyboard::Waker;
+
+
+// シグナルハンドラから書き込む
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static PIPE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+    notify(PIPE_FD.load(Ordering::SeqCst));
+}
+
+/// シグナルハンドラから書き込むパイプを作り、パイプを読むスレッドでwakerを起こす。
+/// 返り値はパイプの書き込む側。作れなければNone
+pub fn wake_pipe(waker: Waker) -> Option<libc::c_int> {
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return None
+    }
+
+    let read_fd = fds[0];
+    thread::spawn(move || {
+        let mut b = 0u8;
+        loop {
+            let n = unsafe { libc::read(read_fd, &mut b as *mut u8 as *mut libc::c_void, 1) };
+            if n > 0 {
+                waker.wake();
+            } else if n == 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                return
+            }
+        }
+    });
+    Some(fds[1])
+}
+
+/// wake_pipeのパイプに書き込む。シグナルハンドラから呼べる。fdが負なら何もしない
+pub fn notify(fd: libc::c_int) {
+    if fd >= 0 {
+        let b = 1u8;
+        unsafe {
+            libc::write(fd, &b as *const u8 as *const libc::c_void, 1);
+        }
+    }
+}
+
+/// SIGINTとSIGTERMのハンドラを設定する。受け取るとwakerでメインのループを起こす
+pub fn install(waker: Waker) {
+    if let Some(fd) = wake_pipe(waker) {
+        PIPE_FD.store(fd, Ordering::SeqCst);
+    }
+
+    // パイプが作れなくても、次にループが起きたときには終了する
+    unsafe {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// SIGINTかSIGTERMを受け取っていればtrue
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use libc;
+    use keyboard::{Waker, WAKE_TYPE};
+    use super::*;
+
+    #[test]
+    fn test_shutdown() {
+        // テストのプロセスにはハンドラを設定せず、ハンドラを直接呼ぶ
+        let (waker, receiver) = Waker::channel();
+        let fd = wake_pipe(waker).unwrap();
+        PIPE_FD.store(fd, Ordering::SeqCst);
+        assert!(!requested());
+
+        on_signal(libc::SIGTERM);
+        assert!(requested());
+        let e = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(e.ty, WAKE_TYPE);
+
+        // 他のテストに影響しないように元に戻す。パイプを閉じるとスレッドも終わる
+        REQUESTED.store(false, Ordering::SeqCst);
+        PIPE_FD.store(-1, Ordering::SeqCst);
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! 記録したイベントを、デバイスを使わずにKeyConverterで変換する。
+//!
+//! イベントの時刻は保ったまま、ソフトウェアでのリピートとtimeoutも再現する。
+//! 記録は最後のイベントで終わるので、それより後のリピートとtimeoutは出力しない。
+//! 結果はevlogの手で書く形式と同じ`<秒> push|leave|repeat <キーの名前>`の行で、
+//! ルールが切り替わったときは`<秒> rules @<ルール名>`の行を出力する。
+
+use std::time::{Duration, Instant};
+
+use evlog;
+use keyboard::RawEvent;
+use key_converter::KeyConverter;
+use key_converter::Output;
+use key_converter::rules::key_rule::Key;
+
+
+/// キーのイベントをkcで変換して、仮想キーボードへの出力を文字列にする
+pub fn run(kc: &mut KeyConverter, events: &[RawEvent]) -> String {
+    let start = Instant::now();
+    let mut out = String::new();
+    let mut name = kc.get_rules_name().to_string();
+
+    for e in events.iter().filter(|e| e.ty == evlog::EV_KEY) {
+        let time = start + e.time;
+
+        // イベントの時刻までのソフトウェアでのリピートとtimeout。
+        // 時刻が進まなくなったら止める
+        let mut last = None;
+        while let Some(d) = kc.next_deadline().filter(|d| *d <= time && Some(*d) != last) {
+            let output = kc.tick(d);
+            write(&mut out, d - start, &output, kc, &mut name);
+            last = Some(d);
+        }
+
+        let output = match e.value {
+            1 => {
+                let (push, leave) = kc.push_at(e.code, time);
+                Output { leave, push, ..Output::default() }
+            },
+            0 => Output { leave: kc.leave_at(e.code, time), ..Output::default() },
+            _ => kc.repeat(e.code)
+        };
+        write(&mut out, e.time, &output, kc, &mut name);
+    }
+    out
+}
+
+/// leave, push, repeatの順に書き、ルールが変わっていればルール名を書く
+fn write(out: &mut String, t: Duration, output: &Output, kc: &KeyConverter, name: &mut String) {
+    let t = evlog::format_time(t);
+    let lines = output.leave.iter().map(|c| (0, c))
+        .chain(output.push.iter().map(|c| (1, c)))
+        .chain(output.repeat.iter().map(|c| (2, c)));
+    for (value, code) in lines {
+        *out += &format!("{} {} {}\n", t, evlog::state_name(value), Key::Raw(*code).to_string());
+    }
+
+    if name != kc.get_rules_name() {
+        *name = kc.get_rules_name().to_string();
+        *out += &format!("{} rules @{}\n", t, name);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use evlog;
+    use key_converter::KeyConverter;
+    use key_converter::RepeatTiming;
+    use super::run;
+
+    #[test]
+    fn test_run() {
+        let mut kc = KeyConverter::new(r#"
+        CAPSLOCK -> 'LEFTCTRL
+        HENKAN -> @SYMBOL
+        @SYMBOL [timeout=1000]
+        J -> 'LEFTBRACE
+        "#.as_bytes()).unwrap();
+        let events = evlog::parse("\
+0.0 push CAPSLOCK
+0.1 push A
+0.2 leave A
+0.3 leave CAPSLOCK
+1.0 push HENKAN
+1.1 leave HENKAN
+1.2 push J
+1.3 leave J
+").unwrap();
+
+        // @SYMBOLのtimeoutは最後のイベントより後なので出力しない
+        assert_eq!(run(&mut kc, &events), "\
+0.000000 push LEFTCTRL
+0.100000 push A
+0.200000 leave A
+0.300000 leave LEFTCTRL
+1.000000 rules @SYMBOL
+1.200000 push LEFTBRACE
+1.300000 leave LEFTBRACE
+");
+        // イベントの間のtimeoutは、その時刻に出力する
+        let events = evlog::parse("0.0 push HENKAN\n0.1 leave HENKAN\n2.0 push J\n").unwrap();
+        kc.select_rules("");
+        assert_eq!(run(&mut kc, &events), "\
+0.000000 rules @SYMBOL
+1.100000 rules @
+2.000000 push J
+");
+    }
+
+    #[test]
+    fn test_run_repeat() {
+        let mut kc = KeyConverter::new("A -> 'B\n".as_bytes()).unwrap();
+        kc.set_repeat_timing(Some(RepeatTiming::new(500, 10)));
+        let events = evlog::parse("0.0 push A\n0.75 leave A\n").unwrap();
+
+        assert_eq!(run(&mut kc, &events), "\
+0.000000 push B
+0.500000 repeat B
+0.600000 repeat B
+0.700000 repeat B
+0.750000 leave B
+");
+
+        // 押したままで記録が終わると、最後のイベントより後のリピートは出力しない
+        let events = evlog::parse("0.0 push A\n0.55 push C\n").unwrap();
+        assert_eq!(run(&mut kc, &events), "\
+0.000000 push B
+0.500000 repeat B
+0.550000 push C
+");
+        assert!(kc.next_deadline().is_some());
+    }
+}
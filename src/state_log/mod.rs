@@ -0,0 +1,109 @@
+//! --show-stateで出力するキーの状態の形式。
+//!
+//! jsonでは1つのイベントを1行のJSONにする。
+//!
+//! ```text
+//! {"time":1760000000.123,"device":"/dev/input/event3","code":58,"name":"CAPSLOCK","type":"push",
+//!  "pressed":["LEFTCTRL"],"released":[],"repeated":[],"rules":"","matched":["CAPSLOCK -> 'LEFTCTRL"]}
+//! ```
+//!
+//! ソフトウェアでのリピートやtimeoutのようにキーボードから読んでいないイベントは、
+//! typeを"tick"にして、device, code, nameをnullにする。
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json;
+
+use key_converter::KeyConverter;
+use key_converter::Output;
+use key_converter::rules::key_rule::Key;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_str(s: &str) -> Option<LogFormat> {
+        match s {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None
+        }
+    }
+}
+
+/// キーボードから読んだキー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Input<'a> {
+    pub device: &'a str,
+    pub code: u16,
+    pub state: i32,
+}
+
+/// 1つのイベントの記録
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Record<'a> {
+    time: f64, // UNIX時間の秒
+    device: Option<&'a str>,
+    code: Option<u16>,
+    name: Option<String>,
+    #[serde(rename = "type")]
+    ty: &'static str,
+    // 仮想キーボードで押した、離した、リピートしたキー
+    pressed: Vec<String>,
+    released: Vec<String>,
+    repeated: Vec<String>,
+    rules: &'a str,
+    matched: Vec<String>,
+}
+
+/// 1つのイベントを1行のJSONにする
+pub fn to_json(time: SystemTime, input: Option<Input>, output: &Output, kc: &KeyConverter) -> String {
+    let names = |codes: &[u16]| codes.iter().map(|c| Key::Raw(*c).to_string()).collect();
+    let record = Record {
+        time: time.duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64()),
+        device: input.map(|i| i.device),
+        code: input.map(|i| i.code),
+        name: input.map(|i| Key::Raw(i.code).to_string()),
+        ty: match input.map(|i| i.state) {
+            Some(0) => "leave",
+            Some(1) => "push",
+            Some(_) => "repeat",
+            None => "tick",
+        },
+        pressed: names(&output.push),
+        released: names(&output.leave),
+        repeated: names(&output.repeat),
+        rules: kc.get_rules_name(),
+        matched: kc.matched_rules(),
+    };
+    serde_json::to_string(&record).unwrap()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+    use key_converter::KeyConverter;
+    use key_converter::Output;
+    use key_converter::rules::key_rule::keycode::*;
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let mut kc = KeyConverter::new(r#"
+        CAPSLOCK -> 'LEFTCTRL
+        "#.as_bytes()).unwrap();
+        let (push, leave) = kc.push(KEY_CAPSLOCK);
+        let output = Output { push, leave, ..Output::default() };
+        let input = Input { device: "/dev/input/event3", code: KEY_CAPSLOCK, state: 1 };
+        let time = UNIX_EPOCH + Duration::from_millis(1500);
+
+        assert_eq!(to_json(time, Some(input), &output, &kc),
+            r#"{"time":1.5,"device":"/dev/input/event3","code":58,"name":"CAPSLOCK","type":"push","pressed":["LEFTCTRL"],"released":[],"repeated":[],"rules":"","matched":["CAPSLOCK -> 'LEFTCTRL"]}"#);
+        assert!(to_json(time, None, &Output::default(), &kc)
+            .contains(r#""device":null,"code":null,"name":null,"type":"tick""#));
+    }
+}
@@ -7,6 +7,8 @@ use std::os::raw::*;
 use std::ffi::CString;
 use std::collections::HashSet;
 
+use key_converter::rules::key_rule::keycode::is_modifier;
+
 extern "C" {
     fn close_virtual_keyboard(fd: c_int) -> c_int;
     fn open_virtual_keyboard(name: *const c_char) -> c_int;
@@ -77,6 +79,24 @@ impl VirtualKeyboard {
     pub fn contains(&self, code: u16) -> bool {
         self.pressed_keys.contains(&code)
     }
+
+    /// 押されているすべてのキーを離す。
+    /// 修飾キーは最後に離して、修飾キーを付けた入力に見えないようにする
+    pub fn release_all(&mut self) {
+        let mut pressed: Vec<u16> = self.pressed_keys.iter().cloned().collect();
+        pressed.sort_by_key(|k| (is_modifier(*k), *k));
+        for code in pressed {
+            self.leave(code);
+        }
+    }
+}
+
+/// 押されたままのキーを離してから仮想キーボードを削除する
+impl Drop for VirtualKeyboard {
+    fn drop(&mut self) {
+        self.release_all();
+        unsafe { close_virtual_keyboard(self.fd); }
+    }
 }
 
 
@@ -85,12 +105,54 @@ impl VirtualKeyboard {
 mod test {
     use std::time::Duration;
     use std::thread;
+    use std::mem;
+    use std::collections::HashSet;
+    use libc;
+    use key_converter::rules::key_rule::keycode::*;
     use super::VirtualKeyboard;
 
     extern "C" {
         fn perror();
     }
 
+    /// uinputの代わりにパイプに書き込ませて、押したキーと離したキーを調べる
+    #[test]
+    fn test_release_on_drop() {
+        let mut fds = [0 as libc::c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut kbd = VirtualKeyboard { fd: fds[1], pressed_keys: HashSet::new() };
+
+        kbd.push(KEY_LEFTSHIFT);
+        kbd.push(KEY_A);
+        kbd.push(KEY_B);
+        kbd.leave(KEY_A);
+        // 修飾キーは最後に離す
+        kbd.release_all();
+        assert!(!kbd.contains(KEY_B) && !kbd.contains(KEY_LEFTSHIFT));
+        kbd.push(KEY_LEFTCTRL);
+        kbd.push(KEY_C);
+        // 押されたままのキーを離す
+        drop(kbd);
+
+        // パイプはUI_DEV_DESTROYに失敗して閉じられていない
+        let mut events = [unsafe { mem::zeroed::<libc::input_event>() }; 20];
+        let size = mem::size_of_val(&events);
+        let n = unsafe { libc::read(fds[0], events.as_mut_ptr() as *mut libc::c_void, size) };
+        assert_eq!(n as usize, size);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+
+        let keys: Vec<(u16, i32)> = events.iter()
+            .filter(|e| e.type_ == 1)
+            .map(|e| (e.code, e.value))
+            .collect();
+        assert_eq!(keys, vec![(KEY_LEFTSHIFT, 1), (KEY_A, 1), (KEY_B, 1), (KEY_A, 0),
+                              (KEY_B, 0), (KEY_LEFTSHIFT, 0),
+                              (KEY_LEFTCTRL, 1), (KEY_C, 1), (KEY_C, 0), (KEY_LEFTCTRL, 0)]);
+    }
+
     #[test]
     #[ignore]
     fn test_kbd_input() {